@@ -1,39 +1,54 @@
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::{
+   StorageBalance, StorageBalanceBounds, StorageManagement,
+};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedMap, Vector};
+use near_sdk::collections::{LookupMap, LookupSet, TreeMap, UnorderedMap};
 use near_sdk::json_types::U128;
 use near_sdk::{
-   env, log, near_bindgen, require, AccountId, Gas, PanicOnDefault, Promise, PromiseOrValue,
+   assert_one_yocto, env, log, near_bindgen, AccountId, Balance, Gas, PanicOnDefault,
+   Promise, PromiseOrValue, PromiseResult,
 };
 use serde_json::json;
+use std::convert::TryFrom;
+use std::ops::Bound;
 
 use crate::msg::{
-   AmountInfo, AprInfo, DepositParam, FarmInfo, FarmResult, PotInfo, Status, UserInfo,
-   WithdrawParam,
+   AmountEvent, AmountInfo, AprEvent, AprInfo, AprTier, BudgetStatus, BufferRateEvent, CoinEvent,
+   CoinInfo, CoinStatement, CompoundingEvent, ConfigEvent, DepositEvent, DepositParam, FarmEvent,
+   FarmInfo, FarmPriceEvent, FarmResult, FarmRunEvent, FundParam, LiquidityBuffer, PauseEvent,
+   PauseFlags, PoolEvent, Position, PotInfo, PotRolloverEvent, ResetEvent, RewardBudget,
+   RewardCredit, RewardEvent, RewardFundEvent, RewardIndex, RewardInfo, RewardStatement,
+   RewardTiers, RewardTiersEvent, RewardToken, RewardTokenEvent, Status, TokenAddressEvent,
+   UpgradeEvent, UserInfo, WithdrawEvent, WithdrawParam, WithdrawRequest, WithdrawRequestEvent,
+   WithdrawRollback, WithdrawSource, WithdrawStatus,
+};
+use crate::error::{ensure, PoolError};
+use crate::math::{
+   accrued_reward, add, compounded_cost, compounded_reward, ln_ratio, mul, mul_div, pow10,
+   scaled_pow, wide_mul, wide_mul_div, REWARD_INDEX_SCALE, U256,
 };
+use crate::migrate::{read_state_version, write_state_version, PoolV1, STATE_VERSION, V1_COINS};
 use crate::util::Check;
 
 const FARM_AMOUNT: u128 = 420_000_000;
 const FARM_PERIOD: u64 = 5_184_000_000; //60 days in msecond
-const REWARD_TIME: u64 = 600_000; //10minutes //24 hours for reward in msecond
+const YEAR: u64 = 31_536_000_000; //365 days in msecond
+const REWARD_RATE_SCALE: u128 = 1_000_000_000_000_000_000;
 const NEART_DECIMALS: u32 = 6;
+const HISTORY_LIMIT: usize = 12;
+const CREDIT_LIMIT: usize = 20;
+const WITHDRAW_LIMIT: usize = 10;
+const POSITION_LIMIT: usize = 10;
 
-pub const COIN_COUNT: usize = 7;
-const COINS: [&str; 7] = ["USDC", "USDT", "DAI", "USN", "wBTC", "ETH", "wNEAR"];
-const DECIMALS: [u32; 7] = [6, 6, 18, 18, 8, 18, 24];
-
-pub fn getcoin_id(coin: String) -> usize {
-   let res = match coin.as_str() {
-      "USDC" => 0,
-      "USDT" => 1,
-      "DAI" => 2,
-      "USN" => 3,
-      "wBTC" => 4,
-      "ETH" => 5,
-      "wNEAR" => 6,
-      _ => env::panic_str("Not correct coin type"),
-   };
-   res
+const GAS_FOR_FT_TRANSFER: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_RESOLVE: Gas = Gas(20_000_000_000_000);
+const GAS_FOR_ON_UPGRADE: Gas = Gas(10_000_000_000_000);
+// left for deploying the code and finishing `upgrade` itself
+const GAS_FOR_DEPLOY: Gas = Gas(30_000_000_000_000);
+
+fn promise_succeeded() -> bool {
+   matches!(env::promise_result(0), PromiseResult::Successful(_))
 }
 
 #[near_bindgen]
@@ -41,7 +56,8 @@ pub fn getcoin_id(coin: String) -> usize {
 pub struct Pool {
    owner: AccountId,
    treasury: AccountId,
-   apr: Vec<u32>,
+   //--------coin registry: index is the coin id--------------
+   coins: Vec<CoinInfo>,
    user_infos: UnorderedMap<AccountId, Vec<UserInfo>>, // for all coin
    total_rewards: Vec<u128>,
    amount_history: Vec<AmountInfo>,
@@ -53,37 +69,318 @@ pub struct Pool {
    //--------qualify----------------------
    pot_infos: UnorderedMap<AccountId, Vec<PotInfo>>,
 
-   //-------_token address--------------------
-   token_address: Vec<AccountId>,
+   //-------storage (NEP-145)--------------------
+   storage_deposits: LookupMap<AccountId, Balance>,
+   account_storage_usage: u64,
+
+   //-------pause: global flags, CoinInfo holds the per coin ones--------------------
+   paused: PauseFlags,
+   guardian: Option<AccountId>,
+
+   //-------lazy rewards: an index per coin, a checkpoint per account and coin--------------------
+   reward_indexes: Vec<RewardIndex>,
+   reward_infos: LookupMap<AccountId, Vec<RewardInfo>>,
+   // per coin, ascending by time. Entries ahead of now are scheduled changes
+   apr_history: Vec<Vec<AprInfo>>,
+   // per coin, ascending by from_index. The last one is in effect
+   reward_tiers: Vec<Vec<RewardTiers>>,
+   reward_budgets: Vec<RewardBudget>,
+   // accounts on simple interest, their reward_amount does not earn and is claimed separately
+   simple_interest: LookupSet<AccountId>,
+   // per coin, None pays rewards in the coin itself
+   reward_tokens: Vec<Option<RewardToken>>,
+   total_token_rewards: Vec<u128>,
+   // the last CREDIT_LIMIT reward credits of each account
+   reward_credits: LookupMap<AccountId, Vec<RewardCredit>>,
+
+   //-------withdrawal queue, requests are kept with their account--------------------
+   // pending ids in order, so paging starts at an id rather than scanning finished ones
+   pending_withdraws: TreeMap<u64, AccountId>,
+   withdraw_requests: LookupMap<AccountId, Vec<WithdrawRequest>>,
+   next_withdraw_id: u64,
+
+   //-------part of each deposit kept on the contract for self-service withdrawals--------------------
+   buffers: Vec<LiquidityBuffer>,
+
+   //-------deposit positions of each account per coin, oldest first--------------------
+   positions: LookupMap<AccountId, Vec<Vec<Position>>>,
+   next_position_id: u64,
 }
 
 #[near_bindgen]
 impl Pool {
    #[init]
    pub fn new(owner: Option<AccountId>, treasury: AccountId) -> Self {
-      let wnear = AccountId::new_unchecked("ft.alenzertest.testnet".to_string());
-      Self {
+      let mut this = Self {
          owner: match owner {
             Some(_owner) => _owner,
             None => env::current_account_id(),
          },
-         treasury: treasury,
-         apr: vec![2149, 2149, 2149, 2149, 876, 876, 1365],
+         treasury,
+         coins: Vec::new(),
          user_infos: UnorderedMap::new(b"n"),
-         total_rewards: vec![0; COIN_COUNT],
+         total_rewards: Vec::new(),
          amount_history: Vec::new(),
          farm_starttime: env::block_timestamp_ms(),
          farm_price: 18,
          farm_infos: UnorderedMap::new(b"f"),
          total_farmed: 0,
          pot_infos: UnorderedMap::new(b"p"),
-         token_address: vec![wnear; COIN_COUNT],
+         storage_deposits: LookupMap::new(b"s"),
+         account_storage_usage: 0,
+         paused: PauseFlags::default(),
+         guardian: None,
+         reward_indexes: Vec::new(),
+         reward_infos: LookupMap::new(b"r"),
+         apr_history: Vec::new(),
+         reward_tiers: Vec::new(),
+         reward_budgets: Vec::new(),
+         simple_interest: LookupSet::new(b"c"),
+         reward_tokens: Vec::new(),
+         total_token_rewards: Vec::new(),
+         reward_credits: LookupMap::new(b"h"),
+         pending_withdraws: TreeMap::new(b"q"),
+         withdraw_requests: LookupMap::new(b"w"),
+         next_withdraw_id: 0,
+         buffers: Vec::new(),
+         positions: LookupMap::new(b"o"),
+         next_position_id: 0,
+      };
+      this.measure_account_storage_usage();
+      write_state_version();
+      this
+   }
+
+   // converts whatever layout is stored into the current one, see migrate.rs
+   #[private]
+   #[init(ignore_state)]
+   pub fn migrate() -> Self {
+      let version = read_state_version();
+      let mut this: Self = match version {
+         1 => Self::from_v1(env::state_read().unwrap_or_else(|| PoolError::NoState.panic())),
+         STATE_VERSION => env::state_read().unwrap_or_else(|| PoolError::NoState.panic()),
+         _ => PoolError::UnknownStateVersion.panic(),
+      };
+      this.measure_account_storage_usage();
+      write_state_version();
+      this
+   }
+
+   fn from_v1(old: PoolV1) -> Self {
+      let coins = V1_COINS
+         .iter()
+         .zip(old.apr.iter().zip(old.token_address.iter()))
+         .map(|((symbol, decimals), (apr, token_address))| CoinInfo {
+            symbol: symbol.to_string(),
+            token_address: token_address.clone(),
+            decimals: *decimals,
+            apr: *apr,
+            enabled: true,
+            paused: PauseFlags::default(),
+         })
+         .collect();
+      // indexes start at zero, so accounts without a checkpoint earn from the migration on
+      let reward_indexes = (0..V1_COINS.len())
+         .map(|coin_id| RewardIndex {
+            index: 0,
+            update_time: env::block_timestamp_ms(),
+            total_base: old.amount_history.last().map_or(0, |last| last.amount[coin_id])
+               + old.total_rewards[coin_id],
+            unsettled: 0,
+         })
+         .collect();
+      // v1 accounts earn from index 0 until they settle, so their checkpoints sit in the first tiers
+      let mut reward_tiers: Vec<Vec<RewardTiers>> =
+         V1_COINS.iter().map(|_| vec![Self::default_reward_tiers()]).collect();
+      for (_, user_info) in old.user_infos.iter() {
+         for (tiers, info) in reward_tiers.iter_mut().zip(user_info.iter()) {
+            if info.amount + info.reward_amount > 0 {
+               tiers[0].checkpoints += 1;
+            }
+         }
+      }
+      let apr_history = old
+         .apr
+         .iter()
+         .map(|apr| {
+            vec![AprInfo {
+               apr: *apr,
+               time: env::block_timestamp_ms(),
+            }]
+         })
+         .collect();
+      Self {
+         owner: old.owner,
+         treasury: old.treasury,
+         coins,
+         user_infos: old.user_infos,
+         total_rewards: old.total_rewards,
+         amount_history: old.amount_history,
+         farm_starttime: old.farm_starttime,
+         farm_price: old.farm_price,
+         farm_infos: old.farm_infos,
+         total_farmed: old.total_farmed,
+         pot_infos: old.pot_infos,
+         storage_deposits: LookupMap::new(b"s"),
+         account_storage_usage: 0,
+         paused: PauseFlags::default(),
+         guardian: None,
+         reward_indexes,
+         reward_infos: LookupMap::new(b"r"),
+         apr_history,
+         reward_tiers,
+         // nothing accrues until the treasury funds a budget
+         reward_budgets: vec![RewardBudget::default(); V1_COINS.len()],
+         simple_interest: LookupSet::new(b"c"),
+         reward_tokens: vec![None; V1_COINS.len()],
+         total_token_rewards: vec![0; V1_COINS.len()],
+         reward_credits: LookupMap::new(b"h"),
+         // v1 reserves are not requests, the treasury fulfils them without an id
+         pending_withdraws: TreeMap::new(b"q"),
+         withdraw_requests: LookupMap::new(b"w"),
+         next_withdraw_id: 0,
+         buffers: vec![LiquidityBuffer::default(); V1_COINS.len()],
+         positions: LookupMap::new(b"o"),
+         next_position_id: 0,
+      }
+   }
+   pub fn get_state_version(&self) -> u32 {
+      read_state_version()
+   }
+
+   // the new wasm is the raw transaction input, not JSON. Deploy and migrate run as one batch,
+   // so a failing migration also reverts the deploy and the old code keeps running.
+   pub fn upgrade(&self) -> Promise {
+      self.check_onlyowner();
+      let code = match env::input() {
+         Some(code) if !code.is_empty() => code,
+         _ => PoolError::NoCode.panic(),
+      };
+      let code_hash = hex::encode(env::sha256(&code));
+      let reserved = env::used_gas() + GAS_FOR_DEPLOY + GAS_FOR_ON_UPGRADE;
+      ensure(env::prepaid_gas() > reserved, PoolError::NotEnoughGas);
+      let migrate_gas = env::prepaid_gas() - reserved;
+      let callback = json!({ "code_hash": code_hash }).to_string().into_bytes();
+
+      Promise::new(env::current_account_id())
+         .deploy_contract(code)
+         .function_call("migrate".to_string(), vec![], 0, migrate_gas)
+         .then(Promise::new(env::current_account_id()).function_call(
+            "on_upgrade".to_string(),
+            callback,
+            0,
+            GAS_FOR_ON_UPGRADE,
+         ))
+   }
+
+   #[private]
+   pub fn on_upgrade(&self, code_hash: String) -> bool {
+      let success = promise_succeeded();
+      PoolEvent::Upgrade(vec![UpgradeEvent {
+         code_hash,
+         success,
+         state_version: read_state_version(),
+      }])
+      .emit();
+      success
+   }
+   pub fn set_guardian(&mut self, guardian: Option<AccountId>) {
+      self.check_onlyowner();
+      self.guardian = guardian;
+      self.emit_config_change();
+   }
+   pub fn get_guardian(&self) -> Option<AccountId> {
+      self.guardian.clone()
+   }
+   // the guardian can only raise flags, lifting them is up to the owner
+   pub fn set_paused(&mut self, coin: Option<String>, paused: PauseFlags) {
+      let caller = env::predecessor_account_id();
+      let current = match &coin {
+         Some(coin) => self.coins[self.coin_id(coin)].paused,
+         None => self.paused,
+      };
+      if self.guardian.as_ref() == Some(&caller) && caller != self.owner {
+         let lifted = (current.deposit && !paused.deposit)
+            || (current.withdraw && !paused.withdraw)
+            || (current.reward && !paused.reward)
+            || (current.farm && !paused.farm)
+            || (current.pot && !paused.pot);
+         ensure(!lifted, PoolError::OnlyOwnerCanUnpause);
+      } else {
+         self.check_onlyowner();
+      }
+
+      // time before the change accrues under the old flags
+      for coin_id in 0..self.coins.len() {
+         self.update_reward_index(coin_id);
+      }
+      match &coin {
+         Some(symbol) => {
+            let coin_id = self.coin_id(symbol);
+            self.coins[coin_id].paused = paused;
+         }
+         None => self.paused = paused,
+      }
+      PoolEvent::Pause(vec![PauseEvent { coin, paused }]).emit();
+   }
+   // flags set on the coin itself, or the global ones when `coin` is None
+   pub fn get_paused(&self, coin: Option<String>) -> PauseFlags {
+      match coin {
+         Some(coin) => self.coins[self.coin_id(&coin)].paused,
+         None => self.paused,
       }
    }
-   pub fn delete_all(&mut self) {
-      self.amount_history.clear();
-      self.user_infos.clear();
-      self.pot_infos.clear();
+   // clears up to `limit` user and pot records per call, returns how many are left
+   pub fn delete_all(&mut self, limit: u64) -> u64 {
+      self.check_onlyowner();
+      ensure(self.paused.deposit, PoolError::NotPaused);
+      if let Some(last) = self.amount_history.last() {
+         ensure(
+            last.amount.iter().all(|amount| *amount == 0),
+            PoolError::UsersHaveDeposits
+         );
+      }
+      ensure(
+         self.total_rewards.iter().all(|amount| *amount == 0)
+            && self.total_token_rewards.iter().all(|amount| *amount == 0),
+         PoolError::UsersHaveRewards
+      );
+
+      let mut removed = 0;
+      let keys: Vec<AccountId> = self.user_infos.keys().take(limit as usize).collect();
+      for key in keys {
+         let user_info = self.user_infos.remove(&key).unwrap_or_default();
+         let reward_info = self.reward_infos.remove(&key).unwrap_or_default();
+         ensure(
+            reward_info.iter().all(|info| info.token_reward_amount == 0),
+            PoolError::UsersHaveRewards
+         );
+         self.remove_checkpoints(&reward_info);
+         self.simple_interest.remove(&key);
+         self.reward_credits.remove(&key);
+         for request in self.withdraw_requests.remove(&key).unwrap_or_default() {
+            self.pending_withdraws.remove(&request.id);
+         }
+         self.positions.remove(&key);
+         ensure(
+            user_info.iter().all(|info| info.amount == 0 && info.reward_amount == 0),
+            PoolError::UsersHaveDeposits
+         );
+         removed += 1;
+      }
+      let keys: Vec<AccountId> =
+         self.pot_infos.keys().take((limit - removed) as usize).collect();
+      for key in keys {
+         self.pot_infos.remove(&key);
+         removed += 1;
+      }
+
+      let remaining = self.user_infos.len() + self.pot_infos.len();
+      if remaining == 0 {
+         self.amount_history.clear();
+      }
+      PoolEvent::Reset(vec![ResetEvent { removed, remaining }]).emit();
+      remaining
    }
    pub fn set_config(&mut self, owner: Option<AccountId>, treasury: Option<AccountId>) {
       self.check_onlyowner();
@@ -93,588 +390,3890 @@ impl Pool {
       if let Some(account) = treasury {
          self.treasury = account;
       }
+      self.emit_config_change();
    }
-   pub fn set_tokenaddress(&mut self, token: [AccountId; COIN_COUNT]) {
+   pub fn add_coin(&mut self, symbol: String, token_address: AccountId, decimals: u32, apr: u32) {
       self.check_onlyowner();
-      for i in 0..COIN_COUNT {
-         self.token_address[i] = token[i].clone();
-      }
+      ensure(
+         !self.coins.iter().any(|info| info.symbol == symbol),
+         PoolError::CoinAlreadyRegistered
+      );
+      ensure(
+         !self.coins.iter().any(|info| info.token_address == token_address),
+         PoolError::TokenAlreadyRegistered
+      );
+      // 10^decimals has to fit u128, or every farm run fails on the coin
+      ensure(decimals <= 38, PoolError::TooManyDecimals);
+
+      self.coins.push(CoinInfo {
+         symbol,
+         token_address,
+         decimals,
+         apr,
+         enabled: true,
+         paused: PauseFlags::default(),
+      });
+      self.total_rewards.push(0);
+      self.reward_indexes.push(RewardIndex {
+         index: 0,
+         update_time: env::block_timestamp_ms(),
+         total_base: 0,
+         unsettled: 0,
+      });
+      self.reward_budgets.push(RewardBudget::default());
+      self.reward_tokens.push(None);
+      self.buffers.push(LiquidityBuffer::default());
+      self.total_token_rewards.push(0);
+      self.apr_history.push(vec![AprInfo {
+         apr,
+         time: env::block_timestamp_ms(),
+      }]);
+      self.reward_tiers.push(vec![Self::default_reward_tiers()]);
+      self.measure_account_storage_usage();
+      self.emit_coin_update(self.coins.len() - 1);
    }
-   pub fn set_apr(&mut self, coin: String, apr: u32) {
+   pub fn disable_coin(&mut self, coin: String) {
       self.check_onlyowner();
-
-      self.apr[getcoin_id(coin)] = apr;
+      let coin_id = self.coin_id(&coin);
+      self.coins[coin_id].enabled = false;
+      self.emit_coin_update(coin_id);
    }
+   pub fn enable_coin(&mut self, coin: String) {
+      self.check_onlyowner();
+      let coin_id = self.coin_id(&coin);
+      self.coins[coin_id].enabled = true;
+      self.emit_coin_update(coin_id);
+   }
+   pub fn set_tokenaddress(&mut self, coin: String, token_address: AccountId) {
+      self.check_onlyowner();
+      let coin_id = self.coin_id(&coin);
+      ensure(
+         !self
+            .coins
+            .iter()
+            .enumerate()
+            .any(|(id, info)| id != coin_id && info.token_address == token_address),
+         PoolError::TokenAlreadyRegistered
+      );
+      let old_token_address =
+         std::mem::replace(&mut self.coins[coin_id].token_address, token_address.clone());
+      PoolEvent::TokenAddressChange(vec![TokenAddressEvent {
+         coin,
+         old_token_address,
+         new_token_address: token_address,
+      }])
+      .emit();
+   }
+   // takes effect at `time` (msecond), now when None. A change at the same time replaces the
+   // scheduled one, time before it keeps accruing at the old rate
+   pub fn set_apr(&mut self, coin: String, apr: u32, time: Option<u64>) {
+      self.check_onlyowner();
+      let coin_id = self.coin_id(&coin);
+      let current_time = env::block_timestamp_ms();
+      let effective_time = time.unwrap_or(current_time);
+      ensure(effective_time >= current_time, PoolError::AprChangeInPast);
+      self.update_reward_index(coin_id);
 
-   #[payable]
-   pub fn withdraw_reserve(&mut self, coin: String, amount: U128) {
-      let _amount: u128 = amount.into();
-      let account = env::signer_account_id();
-      let mut user_info = self.user_infos.get(&account).unwrap();
-      let coin_id = getcoin_id(coin.clone());
-
-      if user_info[coin_id].amount + user_info[coin_id].reward_amount < _amount {
-         return env::panic_str("Not enough balance");
+      let old_apr = self.apr_at(coin_id, effective_time);
+      let history = &mut self.apr_history[coin_id];
+      let position = history.partition_point(|info| info.time < effective_time);
+      if history.get(position).map(|info| info.time) == Some(effective_time) {
+         history[position].apr = apr;
+      } else {
+         history.insert(position, AprInfo { apr, time: effective_time });
       }
-
-      user_info[coin_id].withdraw_reserve = _amount;
-      self.user_infos.insert(&account, &user_info);
+      self.coins[coin_id].apr = self.apr_at(coin_id, current_time);
+      PoolEvent::AprChange(vec![AprEvent {
+         coin,
+         old_apr,
+         new_apr: apr,
+         effective_time,
+      }])
+      .emit();
    }
-
-   fn get_user_info(&self, owner_id: &AccountId) -> Vec<UserInfo> {
-      self.user_infos.get(owner_id).unwrap()
+   // drops a change that has not taken effect yet
+   pub fn cancel_apr_change(&mut self, coin: String, time: u64) {
+      self.check_onlyowner();
+      let coin_id = self.coin_id(&coin);
+      ensure(time > env::block_timestamp_ms(), PoolError::AprChangeInEffect);
+      let history = &mut self.apr_history[coin_id];
+      let position = match history.iter().position(|info| info.time == time) {
+         Some(position) => position,
+         None => PoolError::NoAprChange.panic(),
+      };
+      let removed = history.remove(position);
+      PoolEvent::AprChange(vec![AprEvent {
+         coin,
+         old_apr: removed.apr,
+         new_apr: self.apr_at(coin_id, time),
+         effective_time: time,
+      }])
+      .emit();
    }
+   // bands ascend by up_to and the last one is unbounded. Takes effect from now on, accrual
+   // before it keeps the tiers it was earned under
+   pub fn set_reward_tiers(
+      &mut self,
+      coin: String,
+      tiers: Vec<AprTier>,
+      qualified_multiplier: u32,
+   ) {
+      self.check_onlyowner();
+      let coin_id = self.coin_id(&coin);
+      ensure(
+         tiers.last().is_none_or(|tier| tier.up_to.is_none()),
+         PoolError::LastTierBounded
+      );
+      ensure(
+         tiers.windows(2).all(|pair| match (pair[0].up_to, pair[1].up_to) {
+            (Some(lower), Some(upper)) => lower.0 < upper.0,
+            (Some(_), None) => true,
+            _ => false,
+         }),
+         PoolError::TiersNotAscending
+      );
+      self.update_reward_index(coin_id);
 
-   pub fn rewards(&mut self) {
-      self.check_onlytreasury();
-
-      let available_time = env::block_timestamp_ms() - REWARD_TIME;
-      let keys = self.user_infos.to_vec();
-      let mut bmodified = false;
-      for i in 0..keys.len() {
-         let key = keys[i].0.clone();
-         let mut user_info = self.get_user_info(&key);
-         for coin in COINS {
-            let coin_id = getcoin_id(coin.to_string());
-
-            if user_info[coin_id].deposit_time < available_time {
-               let apr = self.apr[coin_id];
-               let rewards = (user_info[coin_id].amount + user_info[coin_id].reward_amount)
-                  * (apr as u128)
-                  / 10_000
-                  / 365;
-               user_info[coin_id].reward_amount += rewards;
-               self.total_rewards[coin_id] += rewards;
-               if rewards > 0 {
-                  bmodified = true;
-               }
+      let config = RewardTiers {
+         tiers: tiers.clone(),
+         qualified_multiplier,
+         from_index: self.reward_indexes[coin_id].index,
+         checkpoints: 0,
+      };
+      let history = &mut self.reward_tiers[coin_id];
+      match history.last_mut() {
+         Some(last) if last.from_index == config.from_index => {
+            *last = RewardTiers {
+               checkpoints: last.checkpoints,
+               ..config
             }
          }
-         self.user_infos.insert(&key, &user_info);
+         _ => history.push(config),
       }
+      PoolEvent::RewardTiersChange(vec![RewardTiersEvent {
+         coin,
+         tiers,
+         qualified_multiplier,
+      }])
+      .emit();
+   }
+   pub fn get_reward_tiers(&self, coin: String) -> RewardTiers {
+      let coin_id = self.coin_id(&coin);
+      self.reward_tiers[coin_id].last().cloned().unwrap_or_else(Self::default_reward_tiers)
+   }
+   pub fn get_reward_budget(&self, coin: String) -> BudgetStatus {
+      let coin_id = self.coin_id(&coin);
+      let budget = &self.reward_budgets[coin_id];
+      let index_delta = self.reward_index_at(coin_id) - self.reward_indexes[coin_id].index;
+      let remaining = self.remaining_budget(coin_id);
+      let cost = self.accrual_cost(coin_id, index_delta).min(remaining);
+      let reserved = self.reward_indexes[coin_id].unsettled + cost;
+      let remaining = remaining - cost;
 
-      if bmodified && self.amount_history.len() > 0 {
-         let last_index = self.amount_history.len() - 1;
-         let mut info = self.amount_history[last_index].clone();
-         for i in 0..COIN_COUNT {
-            info.reward[i] = self.total_rewards[i];
-         }
-         self.amount_history.push(info);
-
-         if last_index > 10 {
-            let mut retain = vec![true; self.amount_history.len()];
-            retain[0] = false;
-
-            let mut iter = retain.iter();
-            self.amount_history.retain(|_| *iter.next().unwrap());
-         }
+      // a day at today's rate, before compounding
+      let rate = if self.pause_flags(coin_id).reward {
+         0
+      } else {
+         let apr = self.apr_at(coin_id, env::block_timestamp_ms()) as u128;
+         let info = &self.reward_indexes[coin_id];
+         let base = info.total_base + info.unsettled;
+         let weighted = mul_div(base, self.max_multiplier(coin_id), REWARD_INDEX_SCALE);
+         mul_div(weighted, apr, 10_000 * 365)
+      };
+      BudgetStatus {
+         funded: budget.funded,
+         committed: budget.committed,
+         reserved,
+         remaining,
+         shortfall: budget.shortfall,
+         rate,
+         runway: match rate {
+            0 => None,
+            rate => Some(mul_div(remaining, (YEAR / 365) as u128, rate) as u64),
+         },
       }
    }
-
-   pub fn farm(&mut self, price: [u128; COIN_COUNT]) {
-      self.check_onlytreasury();
+   pub fn get_apr_history(&self, coin: String) -> Vec<AprInfo> {
+      self.apr_history[self.coin_id(&coin)].clone()
+   }
+   pub fn get_coins(&self) -> Vec<CoinInfo> {
       let current_time = env::block_timestamp_ms();
-      let farm_starttime = self.farm_starttime;
-      let farm_endtime = farm_starttime + FARM_PERIOD;
-
-      //-----------------condition check------------------------------
-      if farm_starttime == 0 || current_time < farm_starttime {
-         return env::panic_str("NotStartedFarming");
-      }
-
-      let mut total_farm = self.total_farmed;
-
-      let farm_amount = FARM_AMOUNT * (10u128).pow(NEART_DECIMALS);
-
-      if farm_endtime < current_time || total_farm > farm_amount {
-         return;
-      }
-      //--------------------calc farming amount---------------------
-      let mut total_as_usd = 0;
-
-      let keys = self.user_infos.to_vec();
-      for i in 0..keys.len() {
-         let key = keys[i].0.clone();
-         let user_info = self.get_user_info(&key); //(x/10^y) * (price / 10^2) * (10^decimals) /10^3 * 24 = x*price*24/(10^(decimals-y-5))
-         let mut farm = 0;
-
-         for i in 0..COIN_COUNT {
-            let _price: u128 = price[i];
-            farm += user_info[i].amount * _price * 24 / (10u128).pow(DECIMALS[i])
-               * (10u128).pow(NEART_DECIMALS)
-               / 100_000;
-            total_as_usd += user_info[i].amount * _price / (10u128).pow(DECIMALS[i]) / 100;
-         }
-
-         self.update_farm_info(key, farm);
-         total_farm += farm;
+      let mut coins = self.coins.clone();
+      for (coin_id, coin) in coins.iter_mut().enumerate() {
+         coin.apr = self.apr_at(coin_id, current_time);
       }
-
-      self.total_farmed = total_farm;
-      //-------------------recalc token price ------------------------------------
-      //x * (price / 10^2) / 20,000,000
-      let multiple = total_as_usd / (20_000_000u128);
-      //0.18*(1.2)^multiple = 18/10^2 * (12) ^ multiple) /(10^multiple) *10^2
-      let price = 18 * (12u128).pow(multiple as u32) / (10u128).pow(multiple as u32);
-      self.farm_price = price as u128;
+      coins
    }
 
-   pub fn pot_process(&mut self) {
-      self.check_onlytreasury();
-
-      let keys = self.pot_infos.to_vec();
+   // queues a withdrawal request for the treasury, returns its id. withdraw_reserve in
+   // UserInfo is the total still pending for the coin
+   #[payable]
+   pub fn withdraw_reserve(&mut self, coin: String, amount: U128, position_id: Option<u64>) -> u64 {
+      let _amount: u128 = amount.into();
+      let account = env::predecessor_account_id();
+      let mut user_info = self.registered_user_info(&account);
+      let coin_id = self.coin_id(&coin);
+      ensure(!self.pause_flags(coin_id).withdraw, PoolError::WithdrawPaused);
+      ensure(_amount > 0, PoolError::NothingToWithdraw);
+      user_info.resize(self.coins.len(), Self::empty_user_info(&account));
+      self.settle_reward(&account, &mut user_info, coin_id);
 
-      for i in 0..keys.len() {
-         let mut pot_info = keys[i].1.clone();
+      let info = &mut user_info[coin_id];
+      ensure(
+         info.amount + info.reward_amount >= info.withdraw_reserve + _amount,
+         PoolError::NotEnoughBalance
+      );
+      info.withdraw_reserve += _amount;
+      self.check_position(&account, coin_id, info, position_id);
+      self.user_infos.insert(&account, &user_info);
 
-         let mut bnone = true;
-         for j in 0..COIN_COUNT {
-            pot_info[j].qualified_amount = pot_info[j].amount;
-            pot_info[j].amount = 0;
-            if pot_info[j].qualified_amount != 0 {
-               bnone = false;
+      // finished requests make room for new ones, pending ones never drop out
+      let mut requests = self.withdraw_requests.get(&account).unwrap_or_default();
+      if requests.len() >= WITHDRAW_LIMIT {
+         match requests.iter().position(|request| request.status != WithdrawStatus::Pending) {
+            Some(position) => {
+               requests.remove(position);
             }
-         }
-         if bnone == true {
-            self.pot_infos.remove(&keys[i].0);
-         } else {
-            self.pot_infos.insert(&keys[i].0, &pot_info);
+            None => PoolError::TooManyPendingWithdrawals.panic(),
          }
       }
+      let id = self.next_withdraw_id;
+      self.next_withdraw_id += 1;
+      requests.push(WithdrawRequest {
+         id,
+         account: account.clone(),
+         coin: coin.clone(),
+         amount: _amount,
+         time: env::block_timestamp_ms(),
+         status: WithdrawStatus::Pending,
+         position_id,
+      });
+      self.withdraw_requests.insert(&account, &requests);
+      self.pending_withdraws.insert(&id, &account);
+      PoolEvent::WithdrawRequest(vec![WithdrawRequestEvent {
+         id,
+         account,
+         coin,
+         amount,
+      }])
+      .emit();
+      id
    }
-   pub fn get_pot_info(self) -> Vec<Vec<PotInfo>> {
-      let keys = self.pot_infos.to_vec();
-      let mut infos: Vec<Vec<PotInfo>> = vec![];
-      for i in 0..keys.len() {
-         infos.push(keys[i].1.clone())
+
+   // pays out of the coin's liquidity buffer right away. Amounts the buffer can't cover, and
+   // farming accounts whose farm needs the treasury's prices, are queued as with
+   // withdraw_reserve. Returns the request id when queued
+   #[payable]
+   pub fn withdraw(&mut self, coin: String, amount: U128, position_id: Option<u64>) -> Option<u64> {
+      assert_one_yocto();
+      let _amount: u128 = amount.into();
+      let account = env::predecessor_account_id();
+      let coin_id = self.coin_id(&coin);
+      if self.is_farming(&account) {
+         return Some(self.withdraw_reserve(coin, amount, position_id));
       }
-      infos
-   }
-   pub fn get_farm_info(self) -> Vec<FarmResult> {
-      let keys = self.user_infos.to_vec();
-      let mut infos: Vec<FarmResult> = vec![];
-      
-      for i in 0..keys.len() {
-         let farminfo = match self.farm_infos.get(&keys[i].0) {
-            Some(info) => info,
-            None => FarmInfo {
-               account: keys[i].0.clone(),
-               amount: 0,
-            },
-         };
-         infos.push(FarmResult {
-            user_info: keys[i].1.clone(),
-            farm_info: farminfo,
-         })
+      ensure(!self.pause_flags(coin_id).withdraw, PoolError::WithdrawPaused);
+      ensure(_amount > 0, PoolError::NothingToWithdraw);
+      let mut user_info = self.get_user_info(&account);
+      self.settle_reward(&account, &mut user_info, coin_id);
+      let info = &user_info[coin_id];
+      ensure(
+         info.amount + info.reward_amount >= info.withdraw_reserve + _amount,
+         PoolError::NotEnoughBalance
+      );
+      self.check_position(&account, coin_id, info, position_id);
+      // only the principal comes out of the buffer, rewards out of the reward budget
+      let principal = info.amount.min(_amount);
+      self.user_infos.insert(&account, &user_info);
+      if principal > self.buffers[coin_id].balance {
+         return Some(self.withdraw_reserve(coin, amount, position_id));
       }
-      infos
-   }
-   pub fn get_amount_history(self) -> Vec<AmountInfo> {
-      self.amount_history
-   }
 
-   pub fn get_status(self, account: AccountId) -> Status {
-      let userinfo = match self.user_infos.get(&account) {
-         Some(info) => info,
-         None => vec![
-            UserInfo {
-               account: account.clone(),
-               amount: 0,
-               reward_amount: 0,
-               deposit_time: 0,
-               withdraw_reserve: 0
-            };
-            COIN_COUNT
-         ],
-      };
+      self.buffers[coin_id].balance -= principal;
+      let price = vec![0; self.coins.len()];
+      let rollback =
+         self.take_withdraw(account, coin, _amount, price, position_id, WithdrawSource::Buffer);
+      self.send_withdraw(rollback);
+      None
+   }
 
-      let farminfo = match self.farm_infos.get(&account) {
-         Some(info) => info,
-         None => FarmInfo {
-            account: account.clone(),
-            amount: 0,
-         },
+   pub fn cancel_withdraw(&mut self, request_id: u64) {
+      let account = env::predecessor_account_id();
+      let mut requests = self.withdraw_requests.get(&account).unwrap_or_default();
+      let request = match requests.iter_mut().find(|request| request.id == request_id) {
+         Some(request) => request,
+         None => PoolError::NoSuchWithdrawRequest.panic(),
       };
-
-      let potinfo = match self.pot_infos.get(&account) {
-         Some(info) => info,
-         None => vec![
-            PotInfo {
-               account: account.clone(),
-               amount: 0,
-               qualified_amount: 0
-            };
-            COIN_COUNT
-         ],
+      ensure(request.status == WithdrawStatus::Pending, PoolError::WithdrawRequestNotPending);
+      request.status = WithdrawStatus::Cancelled;
+      let event = WithdrawRequestEvent {
+         id: request_id,
+         account: account.clone(),
+         coin: request.coin.clone(),
+         amount: U128(request.amount),
       };
-
-      Status {
-         amount_history: self.amount_history,
-         user_info: userinfo,
-         farm_price: self.farm_price,
-         farm_info: farminfo,
-         farm_starttime: self.farm_starttime,
-         total_rewards: self.total_rewards,
-         pot_info: potinfo,
-      }
+      let coin_id = self.coin_id(&request.coin);
+      let mut user_info = self.get_user_info(&account);
+      user_info[coin_id].withdraw_reserve =
+         user_info[coin_id].withdraw_reserve.saturating_sub(request.amount);
+      self.user_infos.insert(&account, &user_info);
+      self.withdraw_requests.insert(&account, &requests);
+      self.pending_withdraws.remove(&request_id);
+      PoolEvent::WithdrawCancel(vec![event]).emit();
    }
-}
 
-impl Check for Pool {
-   fn check_onlyowner(&self) {
-      if self.owner != env::predecessor_account_id() {
-         env::panic_str("Not Authorized")
-      }
+   // pending requests of every account, oldest first from id `from_id` on. The next page
+   // starts one past the last id returned
+   pub fn get_pending_withdraws(&self, from_id: u64, limit: u64) -> Vec<WithdrawRequest> {
+      self.pending_withdraws
+         .range((Bound::Included(from_id), Bound::Unbounded))
+         .take(limit as usize)
+         .filter_map(|(id, account)| {
+            let requests = self.withdraw_requests.get(&account)?;
+            requests.into_iter().find(|request| request.id == id)
+         })
+         .collect()
    }
-   fn check_onlytreasury(&self) {
-      if self.treasury != env::predecessor_account_id() {
-         env::panic_str("Only treasury")
-      }
+   pub fn get_withdraw_requests(&self, account: AccountId) -> Vec<WithdrawRequest> {
+      self.withdraw_requests.get(&account).unwrap_or_default()
    }
 
-   fn deposit(&mut self, coin: String, amount: u128, qualified: bool) {
-      let account = env::signer_account_id();
-      let coin_id = getcoin_id(coin.clone());
-      if let Some(mut user_info) = self.user_infos.get(&account) {
-         user_info[coin_id].amount += amount;
-         user_info[coin_id].deposit_time = env::block_timestamp_ms();
+   // accrued as of the account's last settlement in the coin
+   pub fn get_positions(&self, account: AccountId, coin: String) -> Vec<Position> {
+      let coin_id = self.coin_id(&coin);
+      let user_info = self.get_user_info(&account);
+      self.positions_of(&account, coin_id, &user_info[coin_id])
+   }
 
-         self.user_infos.insert(&account, &user_info);
+   // switching settles every coin first, so the old mode covers everything earned until now
+   pub fn set_compounding(&mut self, compounding: bool) {
+      let account = env::predecessor_account_id();
+      ensure(self.storage_deposits.contains_key(&account), PoolError::AccountNotRegistered);
+      let mut user_info = self.get_user_info(&account);
+      for coin_id in 0..self.coins.len() {
+         self.settle_reward(&account, &mut user_info, coin_id);
+      }
+      self.user_infos.insert(&account, &user_info);
+      if compounding {
+         self.simple_interest.remove(&account);
       } else {
-         let mut user_info = vec![
-            UserInfo {
-               account: account.clone(),
-               amount: 0,
-               reward_amount: 0,
-               deposit_time: 0,
-               withdraw_reserve: 0,
-            };
-            7
-         ];
-         user_info[coin_id].amount = amount;
-         user_info[coin_id].deposit_time = env::block_timestamp_ms();
-
-         self.user_infos.insert(&account, &user_info);
+         self.simple_interest.insert(&account);
       }
+      PoolEvent::CompoundingChange(vec![CompoundingEvent {
+         account,
+         compounding,
+      }])
+      .emit();
+   }
 
-      self.append_amount_history(coin.clone(), amount, true);
-      self.deposit_potinfo(account.clone(), coin.clone(), amount, qualified);
+   // pays the whole reward_amount out of the funded reward budget, principal stays
+   #[payable]
+   pub fn claim_rewards(&mut self, coin: String) -> Promise {
+      assert_one_yocto();
+      let account = env::predecessor_account_id();
+      let coin_id = self.coin_id(&coin);
+      ensure(!self.pause_flags(coin_id).withdraw, PoolError::WithdrawPaused);
+      let mut user_info = self.get_user_info(&account);
+      self.settle_reward(&account, &mut user_info, coin_id);
+
+      let amount = user_info[coin_id].reward_amount;
+      ensure(amount > 0, PoolError::NoRewardsToClaim);
+      ensure(
+         user_info[coin_id].amount >= user_info[coin_id].withdraw_reserve,
+         PoolError::RewardsReserved
+      );
+      user_info[coin_id].reward_amount = 0;
+      self.user_infos.insert(&account, &user_info);
+      self.total_rewards[coin_id] -= amount;
+      self.reward_indexes[coin_id].total_base -= amount;
+      self.append_amount_history(coin.clone(), 0, true);
+      PoolEvent::Claim(vec![AmountEvent {
+         account: account.clone(),
+         coin: coin.clone(),
+         amount: U128(amount),
+      }])
+      .emit();
 
-      let arguments = json!({ "receiver_id": self.treasury.to_string(), "amount": amount.to_string() }) // method arguments
+      let arguments = json!({ "receiver_id": account.to_string(), "amount": amount.to_string() })
          .to_string()
          .into_bytes();
-      Promise::new(self.token_address[coin_id].clone()).function_call(
-         "ft_transfer".to_string(),
-         arguments,
-         1,
-         Gas(5_000_000_000_000),
-      );
+      let callback = json!({ "account": account, "coin": coin, "amount": U128(amount) })
+         .to_string()
+         .into_bytes();
+      Promise::new(self.coins[coin_id].token_address.clone())
+         .function_call("ft_transfer".to_string(), arguments, 1, GAS_FOR_FT_TRANSFER)
+         .then(Promise::new(env::current_account_id()).function_call(
+            "on_claim_complete".to_string(),
+            callback,
+            0,
+            GAS_FOR_RESOLVE,
+         ))
    }
 
-   fn withdraw(
+   // rewards settled from now on are paid in `reward_token`, None goes back to the coin itself.
+   // The budget is kept in coin units, so what the tokens on hand cover is priced again at the
+   // new rate: rewards reserved but not settled yet come first, what is left stays to accrue.
+   // Another token hands the old one's budget back to the treasury and starts short of what
+   // is reserved
+   pub fn set_reward_token(
       &mut self,
-      account: AccountId,
       coin: String,
-      amount: u128,
-      price: [u128; COIN_COUNT],
-   ) {
-      let mut user_info = self.user_infos.get(&account).unwrap();
-      let coin_id = getcoin_id(coin.clone());
-      if user_info[coin_id].withdraw_reserve < amount {
-         return env::panic_str("Not enough reserved");
-      }
-
-      if user_info[coin_id].amount + user_info[coin_id].reward_amount < amount {
-         return env::panic_str("Not enough balance");
+      reward_token: Option<RewardToken>,
+   ) -> PromiseOrValue<U128> {
+      self.check_onlyowner();
+      let coin_id = self.coin_id(&coin);
+      if let Some(reward_token) = &reward_token {
+         ensure(reward_token.rate.0 > 0, PoolError::RateNotPositive);
       }
+      self.update_reward_index(coin_id);
+      let (old_token, old_rate) = self.paid_in(coin_id);
+      self.reward_tokens[coin_id] = reward_token.clone();
+      let (token, rate) = self.paid_in(coin_id);
 
-      let remain;
-      if user_info[coin_id].amount >= amount {
-         remain = amount;
-         user_info[coin_id].amount -= amount;
+      let unsettled = self.reward_indexes[coin_id].unsettled;
+      let remaining = self.remaining_budget(coin_id);
+      let budget = &mut self.reward_budgets[coin_id];
+      let backed = remaining + unsettled.saturating_sub(budget.shortfall);
+      let (kept, freed) = if token == old_token {
+         (mul_div(backed, old_rate, rate), 0)
       } else {
-         remain = user_info[coin_id].amount;
-         user_info[coin_id].amount = 0;
-         user_info[coin_id].reward_amount -= amount - remain;
+         (0, backed)
+      };
+      budget.shortfall = unsettled.saturating_sub(kept);
+      budget.funded = budget.committed + unsettled + kept.saturating_sub(unsettled);
+      PoolEvent::RewardTokenChange(vec![RewardTokenEvent { coin, reward_token }]).emit();
 
-         self.total_rewards[coin_id] -= amount - remain;
+      let tokens = mul_div(freed, old_rate, REWARD_RATE_SCALE);
+      if tokens == 0 {
+         return PromiseOrValue::Value(U128(0));
       }
-      user_info[coin_id].withdraw_reserve = 0;
+      self.defund(coin_id, old_token, tokens).into()
+   }
+   pub fn get_reward_token(&self, coin: String) -> Option<RewardToken> {
+      self.reward_tokens[self.coin_id(&coin)].clone()
+   }
 
-      self.append_amount_history(coin.clone(), remain, false);
-      self.withdraw_potinfo(account.clone(), coin.clone(), remain);
-      self.farm_withdraw(account.clone(), coin.clone(), remain, price);
+   // basis points of each later deposit kept on the contract instead of forwarded to the treasury
+   pub fn set_buffer_rate(&mut self, coin: String, rate: u32) {
+      self.check_onlyowner();
+      ensure(rate <= 10_000, PoolError::RateTooHigh);
+      let coin_id = self.coin_id(&coin);
+      self.buffers[coin_id].rate = rate;
+      PoolEvent::BufferRateChange(vec![BufferRateEvent { coin, rate }]).emit();
+   }
+   pub fn get_liquidity_buffer(&self, coin: String) -> LiquidityBuffer {
+      self.buffers[self.coin_id(&coin)].clone()
+   }
+
+   // pays token_reward_amount in the reward token, out of what the treasury funded
+   #[payable]
+   pub fn claim_token_rewards(&mut self, coin: String) -> Promise {
+      assert_one_yocto();
+      let account = env::predecessor_account_id();
+      let coin_id = self.coin_id(&coin);
+      ensure(!self.pause_flags(coin_id).withdraw, PoolError::WithdrawPaused);
+      let reward_token = match &self.reward_tokens[coin_id] {
+         Some(reward_token) => reward_token.token_address.clone(),
+         None => PoolError::NoRewardToken.panic(),
+      };
+      let mut user_info = self.get_user_info(&account);
+      self.settle_reward(&account, &mut user_info, coin_id);
+      self.user_infos.insert(&account, &user_info);
 
-      self.user_infos.insert(&account.clone(), &user_info);
+      let mut reward_info = self.get_reward_info(&account);
+      let amount = reward_info[coin_id].token_reward_amount;
+      ensure(amount > 0, PoolError::NoRewardsToClaim);
+      reward_info[coin_id].token_reward_amount = 0;
+      self.reward_infos.insert(&account, &reward_info);
+      self.total_token_rewards[coin_id] -= amount;
+      PoolEvent::TokenClaim(vec![AmountEvent {
+         account: account.clone(),
+         coin: coin.clone(),
+         amount: U128(amount),
+      }])
+      .emit();
 
-      let arguments = json!({ "receiver_id": account.to_string(), "amount": amount.to_string() }) // method arguments
+      let arguments = json!({ "receiver_id": account.to_string(), "amount": amount.to_string() })
          .to_string()
          .into_bytes();
-      Promise::new(self.token_address[coin_id].clone()).function_call(
-         "ft_transfer".to_string(),
-         arguments,
-         1,
-         Gas(5_000_000_000_000),
-      );
+      let callback = json!({ "account": account, "coin": coin, "amount": U128(amount) })
+         .to_string()
+         .into_bytes();
+      Promise::new(reward_token)
+         .function_call("ft_transfer".to_string(), arguments, 1, GAS_FOR_FT_TRANSFER)
+         .then(Promise::new(env::current_account_id()).function_call(
+            "on_token_claim_complete".to_string(),
+            callback,
+            0,
+            GAS_FOR_RESOLVE,
+         ))
    }
-   fn append_amount_history(&mut self, coin: String, amount: u128, bAdd: bool) {
-      let coin_id = getcoin_id(coin.clone());
-      if self.amount_history.len() == 0 {
-         let mut amounts = vec![0; 7];
-         let rewards = vec![0; 7];
-         amounts[coin_id] = amount;
-
-         self.amount_history.push(AmountInfo {
-            amount: amounts,
-            reward: rewards,
-            time: env::block_timestamp_ms(),
-         });
-      } else {
-         let last_index = self.amount_history.len() - 1;
-         let mut info = self.amount_history[last_index].clone();
 
-         if bAdd {
-            info.amount[coin_id] += amount;
-         } else {
-            info.amount[coin_id] -= amount;
-         }
-         info.time = env::block_timestamp_ms();
-         info.reward[coin_id] = self.total_rewards[coin_id];
+   // hands unspent budget back to the treasury in the token the rewards are paid in. `amount`
+   // is in coin units, like get_reward_budget's remaining
+   #[payable]
+   pub fn defund_rewards(&mut self, coin: String, amount: U128) -> Promise {
+      assert_one_yocto();
+      self.check_onlytreasury();
+      let coin_id = self.coin_id(&coin);
+      self.update_reward_index(coin_id);
+      ensure(amount.0 > 0, PoolError::NothingToWithdraw);
+      ensure(amount.0 <= self.remaining_budget(coin_id), PoolError::NotEnoughBudget);
+      self.reward_budgets[coin_id].funded -= amount.0;
+      let (token, rate) = self.paid_in(coin_id);
+      self.defund(coin_id, token, mul_div(amount.0, rate, REWARD_RATE_SCALE))
+   }
 
-         self.amount_history.push(info);
+   fn emit_config_change(&self) {
+      PoolEvent::ConfigChange(vec![ConfigEvent {
+         owner: self.owner.clone(),
+         treasury: self.treasury.clone(),
+         guardian: self.guardian.clone(),
+      }])
+      .emit();
+   }
 
-         if last_index > 10 {
-            let mut retain = vec![true; self.amount_history.len()];
-            retain[0] = false;
+   fn emit_coin_update(&self, coin_id: usize) {
+      let coin = &self.coins[coin_id];
+      PoolEvent::CoinUpdate(vec![CoinEvent {
+         coin: coin.symbol.clone(),
+         token_address: coin.token_address.clone(),
+         decimals: coin.decimals,
+         apr: self.apr_at(coin_id, env::block_timestamp_ms()),
+         enabled: coin.enabled,
+      }])
+      .emit();
+   }
 
-            let mut iter = retain.iter();
-            self.amount_history.retain(|_| *iter.next().unwrap());
-         }
+   fn coin_id(&self, coin: &str) -> usize {
+      match self.coins.iter().position(|info| info.symbol == coin) {
+         Some(coin_id) => coin_id,
+         None => PoolError::UnknownCoin.panic(),
       }
    }
 
-   fn deposit_potinfo(&mut self, account: AccountId, coin: String, amount: u128, qualified: bool) {
-      let mut pot_info = if let Some(info) = self.pot_infos.get(&account) {
-         info
+   // bytes a depositor costs across user_infos, reward_infos, pot_infos, farm_infos at the current coin count
+   fn measure_account_storage_usage(&mut self) {
+      let initial_storage_usage = env::storage_usage();
+      let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+      self.storage_deposits.insert(&tmp_account_id, &0);
+      self.user_infos.insert(&tmp_account_id, &self.get_user_info(&tmp_account_id));
+      self.reward_infos.insert(&tmp_account_id, &self.get_reward_info(&tmp_account_id));
+      self.simple_interest.insert(&tmp_account_id);
+      self.reward_credits.insert(&tmp_account_id, &vec![RewardCredit::default(); CREDIT_LIMIT]);
+      let symbol = self.coins.iter().map(|info| info.symbol.clone()).max_by_key(String::len);
+      let requests: Vec<WithdrawRequest> = (0..WITHDRAW_LIMIT as u64)
+         .map(|index| WithdrawRequest {
+            id: u64::MAX - index,
+            account: tmp_account_id.clone(),
+            coin: symbol.clone().unwrap_or_default(),
+            amount: 0,
+            time: 0,
+            status: WithdrawStatus::Pending,
+            position_id: Some(0),
+         })
+         .collect();
+      // every queued id costs the same, one stands for all of them
+      let queue_storage_usage = env::storage_usage();
+      self.pending_withdraws.insert(&u64::MAX, &tmp_account_id);
+      let queue_usage = (env::storage_usage() - queue_storage_usage) * WITHDRAW_LIMIT as u64;
+      self.pending_withdraws.remove(&u64::MAX);
+      self.withdraw_requests.insert(&tmp_account_id, &requests);
+      let position = Position {
+         id: 0,
+         amount: U128(0),
+         start: 0,
+         term: 0,
+         accrued: U128(0),
+      };
+      let positions = vec![vec![position; POSITION_LIMIT]; self.coins.len()];
+      self.positions.insert(&tmp_account_id, &positions);
+      self.pot_infos.insert(&tmp_account_id, &self.get_pot_info_of(&tmp_account_id));
+      self.farm_infos.insert(
+         &tmp_account_id,
+         &FarmInfo {
+            account: tmp_account_id.clone(),
+            amount: 0,
+         },
+      );
+      self.account_storage_usage = env::storage_usage() - initial_storage_usage + queue_usage;
+      self.storage_deposits.remove(&tmp_account_id);
+      self.user_infos.remove(&tmp_account_id);
+      self.reward_infos.remove(&tmp_account_id);
+      self.simple_interest.remove(&tmp_account_id);
+      self.reward_credits.remove(&tmp_account_id);
+      self.withdraw_requests.remove(&tmp_account_id);
+      self.positions.remove(&tmp_account_id);
+      self.pot_infos.remove(&tmp_account_id);
+      self.farm_infos.remove(&tmp_account_id);
+   }
+
+   fn storage_min_balance(&self) -> Balance {
+      Balance::from(self.account_storage_usage) * env::storage_byte_cost()
+   }
+
+   fn storage_balance(&self, account: &AccountId) -> StorageBalance {
+      let balance = self.storage_deposits.get(account).unwrap_or_default();
+      StorageBalance {
+         total: balance.into(),
+         available: balance.saturating_sub(self.storage_min_balance()).into(),
+      }
+   }
+
+   // registered and still covering a record sized for every listed coin
+   fn is_storage_covered(&self, account: &AccountId) -> bool {
+      match self.storage_deposits.get(account) {
+         Some(balance) => balance >= self.storage_min_balance(),
+         None => false,
+      }
+   }
+
+   // global flags combined with the coin's own
+   fn pause_flags(&self, coin_id: usize) -> PauseFlags {
+      let global = self.paused;
+      let coin = self.coins[coin_id].paused;
+      PauseFlags {
+         deposit: global.deposit || coin.deposit,
+         withdraw: global.withdraw || coin.withdraw,
+         reward: global.reward || coin.reward,
+         farm: global.farm || coin.farm,
+         pot: global.pot || coin.pot,
+      }
+   }
+
+   fn coin_id_by_token(&self, token: &AccountId) -> Option<usize> {
+      self.coins.iter().position(|info| &info.token_address == token)
+   }
+
+   fn msg_coin_matches(msg_coin: &Option<String>, coin: &str) -> bool {
+      match msg_coin {
+         Some(msg_coin) if msg_coin != coin => {
+            log!("Coin {} in msg does not match token coin {}", msg_coin, coin);
+            false
+         }
+         _ => true,
+      }
+   }
+
+   fn empty_user_info(account: &AccountId) -> UserInfo {
+      UserInfo {
+         account: account.clone(),
+         amount: 0,
+         reward_amount: 0,
+         deposit_time: 0,
+         withdraw_reserve: 0,
+      }
+   }
+
+   fn empty_pot_info(account: &AccountId) -> PotInfo {
+      PotInfo {
+         account: account.clone(),
+         amount: 0,
+         qualified_amount: 0,
+      }
+   }
+
+   // records written before a coin was registered are padded on read
+   fn get_user_info(&self, owner_id: &AccountId) -> Vec<UserInfo> {
+      let mut user_info = self.user_infos.get(owner_id).unwrap_or_default();
+      user_info.resize(self.coins.len(), Self::empty_user_info(owner_id));
+      user_info
+   }
+
+   fn get_pot_info_of(&self, owner_id: &AccountId) -> Vec<PotInfo> {
+      let mut pot_info = self.pot_infos.get(owner_id).unwrap_or_default();
+      pot_info.resize(self.coins.len(), Self::empty_pot_info(owner_id));
+      pot_info
+   }
+
+   fn get_reward_info(&self, owner_id: &AccountId) -> Vec<RewardInfo> {
+      let mut reward_info = self.reward_infos.get(owner_id).unwrap_or_default();
+      reward_info.resize(self.coins.len(), RewardInfo::default());
+      reward_info
+   }
+
+   // the rate in effect at `time`
+   fn apr_at(&self, coin_id: usize, time: u64) -> u32 {
+      let history = &self.apr_history[coin_id];
+      match history.partition_point(|info| info.time <= time) {
+         0 => history.first().map_or(0, |info| info.apr),
+         position => history[position - 1].apr,
+      }
+   }
+
+   // the highest multiplier the current tiers pay, REWARD_INDEX_SCALE being the coin apr
+   fn max_multiplier(&self, coin_id: usize) -> u128 {
+      self.reward_tiers[coin_id].last().map_or(REWARD_INDEX_SCALE, Self::tiers_max_multiplier)
+   }
+   fn tiers_max_multiplier(config: &RewardTiers) -> u128 {
+      let multiplier = config.tiers.iter().map(|tier| tier.multiplier).max().unwrap_or(10_000);
+      let qualified_multiplier = config.qualified_multiplier.max(10_000);
+      mul_div(
+         REWARD_INDEX_SCALE,
+         (multiplier as u128) * (qualified_multiplier as u128),
+         100_000_000,
+      )
+   }
+
+   // what accrual may cost at most while the index moves by `index_delta`: every unit of the
+   // coin, settled or not, compounding at the highest multiplier. Settling accounts hand back
+   // their share of it, see charged_cost
+   fn accrual_cost(&self, coin_id: usize, index_delta: u128) -> u128 {
+      if index_delta == 0 {
+         return 0;
+      }
+      let info = &self.reward_indexes[coin_id];
+      // one more for what accounts settling over other spans may round up to
+      let exponent = accrued_reward(self.max_multiplier(coin_id), index_delta) + 1;
+      compounded_cost(info.total_base + info.unsettled, exponent)
+   }
+
+   // the coin's index as of now, accrued piecewise across rate changes and capped by what is
+   // left of the reward budget. Time with rewards paused does not count
+   fn reward_index_at(&self, coin_id: usize) -> u128 {
+      let info = &self.reward_indexes[coin_id];
+      let current_time = env::block_timestamp_ms();
+      if current_time <= info.update_time || self.pause_flags(coin_id).reward {
+         return info.index;
+      }
+      let history = &self.apr_history[coin_id];
+      let mut index = info.index;
+      let mut from = info.update_time;
+      while from < current_time {
+         let next = history.partition_point(|apr_info| apr_info.time <= from);
+         let until = history
+            .get(next)
+            .map_or(current_time, |apr_info| apr_info.time.min(current_time));
+         let elapsed = (until - from) as u128;
+         let apr = self.apr_at(coin_id, from) as u128;
+         index += mul_div(apr * elapsed, REWARD_INDEX_SCALE, 10_000 * (YEAR as u128));
+         from = until;
+      }
+
+      let remaining = self.remaining_budget(coin_id);
+      if self.accrual_cost(coin_id, index - info.index) > remaining {
+         // compounding turns base into base + remaining at that exponent
+         let base = info.total_base + info.unsettled;
+         let exponent = ln_ratio(base + remaining, base);
+         let multiplier = self.max_multiplier(coin_id).max(1);
+         index = info.index + mul_div(exponent, REWARD_INDEX_SCALE, multiplier);
+      }
+      index
+   }
+
+   // accrual reserves budget at the tiers' highest multiplier until accounts settle, what
+   // accounts settle stays within it
+   fn update_reward_index(&mut self, coin_id: usize) {
+      let index = self.reward_index_at(coin_id);
+      let current_time = env::block_timestamp_ms();
+      let index_delta = index - self.reward_indexes[coin_id].index;
+      // reward_index_at keeps the cost within the budget, up to rounding
+      let cost = self.accrual_cost(coin_id, index_delta).min(self.remaining_budget(coin_id));
+      let info = &mut self.reward_indexes[coin_id];
+      info.unsettled += cost;
+      info.index = index;
+      info.update_time = info.update_time.max(current_time);
+      self.coins[coin_id].apr = self.apr_at(coin_id, current_time);
+   }
+
+   // funding neither settled nor reserved for rewards not settled yet
+   fn remaining_budget(&self, coin_id: usize) -> u128 {
+      let budget = &self.reward_budgets[coin_id];
+      budget.funded.saturating_sub(budget.committed + self.reward_indexes[coin_id].unsettled)
+   }
+
+   // the token the coin's rewards are paid in, and its rate per coin unit
+   fn paid_in(&self, coin_id: usize) -> (AccountId, u128) {
+      match &self.reward_tokens[coin_id] {
+         Some(reward_token) => (reward_token.token_address.clone(), reward_token.rate.0),
+         None => (self.coins[coin_id].token_address.clone(), REWARD_RATE_SCALE),
+      }
+   }
+
+   // the coin whose rewards `token` pays: the msg's coin, or the coin of `token` itself
+   fn reward_fund_target(&self, token: &AccountId, msg_coin: &Option<String>) -> Option<usize> {
+      let coin_id = match msg_coin {
+         Some(msg_coin) => self.coins.iter().position(|info| &info.symbol == msg_coin)?,
+         None => self.coin_id_by_token(token)?,
+      };
+      (&self.paid_in(coin_id).0 == token).then_some(coin_id)
+   }
+
+   // sends `tokens` of `token`, already taken out of the budget, to the treasury
+   fn defund(&self, coin_id: usize, token: AccountId, tokens: u128) -> Promise {
+      let coin = self.coins[coin_id].symbol.clone();
+      PoolEvent::RewardDefund(vec![RewardFundEvent {
+         coin: coin.clone(),
+         amount: U128(tokens),
+         funded: U128(self.reward_budgets[coin_id].funded),
+      }])
+      .emit();
+
+      let arguments = json!({ "receiver_id": self.treasury.to_string(), "amount": tokens.to_string() })
+         .to_string()
+         .into_bytes();
+      let callback = json!({ "coin": coin, "token": token, "tokens": U128(tokens) })
+         .to_string()
+         .into_bytes();
+      Promise::new(token)
+         .function_call("ft_transfer".to_string(), arguments, 1, GAS_FOR_FT_TRANSFER)
+         .then(Promise::new(env::current_account_id()).function_call(
+            "on_defund_complete".to_string(),
+            callback,
+            0,
+            GAS_FOR_RESOLVE,
+         ))
+   }
+
+   // takes a settled withdrawal out of the account, principal first. send_withdraw pays it
+   fn take_withdraw(
+      &mut self,
+      account: AccountId,
+      coin: String,
+      amount: u128,
+      price: Vec<u128>,
+      position_id: Option<u64>,
+      source: WithdrawSource,
+   ) -> WithdrawRollback {
+      let coin_id = self.coin_id(&coin);
+      let mut user_info = self.get_user_info(&account);
+      let request_id = match source {
+         WithdrawSource::Request(request_id) => Some(request_id),
+         _ => None,
+      };
+      if user_info[coin_id].amount + user_info[coin_id].reward_amount < amount {
+         PoolError::NotEnoughBalance.panic();
+      }
+
+      let mut positions = self.positions_of(&account, coin_id, &user_info[coin_id]);
+      let remain;
+      if user_info[coin_id].amount >= amount {
+         remain = amount;
+         user_info[coin_id].amount -= amount;
       } else {
-         vec![
-            PotInfo {
-               account: account.clone(),
-               amount: 0,
-               qualified_amount: 0
-            };
-            COIN_COUNT
-         ]
+         remain = user_info[coin_id].amount;
+         user_info[coin_id].amount = 0;
+         user_info[coin_id].reward_amount -= amount - remain;
+
+         self.total_rewards[coin_id] -= amount - remain;
+      }
+      let taken = Self::take_positions(&mut positions, remain, position_id);
+      self.store_positions(&account, coin_id, positions, &mut user_info[coin_id]);
+      self.reward_indexes[coin_id].total_base -= amount;
+
+      self.append_amount_history(coin.clone(), remain, false);
+      let (pot_qualified_amount, pot_amount) =
+         self.withdraw_potinfo(account.clone(), coin.clone(), remain);
+      let farm_amount = self.farm_withdraw(account.clone(), coin.clone(), remain, price);
+
+      self.user_infos.insert(&account, &user_info);
+      PoolEvent::Withdraw(vec![WithdrawEvent {
+         account: account.clone(),
+         coin: coin.clone(),
+         amount: U128(amount),
+         reward_amount: U128(amount - remain),
+         request_id,
+      }])
+      .emit();
+
+      WithdrawRollback {
+         account,
+         coin,
+         amount: U128(amount),
+         reward_amount: U128(amount - remain),
+         source,
+         positions: taken,
+         pot_qualified_amount: U128(pot_qualified_amount),
+         pot_amount: U128(pot_amount),
+         farm_amount: U128(farm_amount),
+         surplus: U128(0),
+      }
+   }
+
+   fn send_withdraw(&self, rollback: WithdrawRollback) -> Promise {
+      let coin_id = self.coin_id(&rollback.coin);
+      let receiver_id = rollback.account.to_string();
+      let arguments = json!({ "receiver_id": receiver_id, "amount": rollback.amount.0.to_string() })
+         .to_string()
+         .into_bytes();
+      let callback = json!({ "rollback": rollback }).to_string().into_bytes();
+      Promise::new(self.coins[coin_id].token_address.clone())
+         .function_call("ft_transfer".to_string(), arguments, 1, GAS_FOR_FT_TRANSFER)
+         .then(Promise::new(env::current_account_id()).function_call(
+            "on_withdraw_complete".to_string(),
+            callback,
+            0,
+            GAS_FOR_RESOLVE,
+         ))
+   }
+
+   // the account's records, failing for accounts that never deposited
+   fn registered_user_info(&self, account: &AccountId) -> Vec<UserInfo> {
+      match self.user_infos.get(account) {
+         Some(user_info) => user_info,
+         None => PoolError::AccountNotRegistered.panic(),
+      }
+   }
+
+   // ft_on_transfer hands the whole amount back, saying why
+   fn refund(error: PoolError, amount: U128) -> PromiseOrValue<U128> {
+      log!("{}", error);
+      PromiseOrValue::Value(amount)
+   }
+
+   // farm_withdraw needs prices, which only the treasury passes
+   fn is_farming(&self, account: &AccountId) -> bool {
+      let now = env::block_timestamp_ms();
+      let farm_endtime = self.farm_starttime + FARM_PERIOD;
+      self.farm_starttime != 0
+         && self.farm_starttime <= now
+         && now <= farm_endtime
+         && self.farm_infos.get(account).is_some()
+   }
+
+   // what a treasury transfer of `amount` pays out, with the request and position it is for.
+   // Without an id, the oldest request of exactly this amount, then the oldest one the
+   // transfer covers, then a reserve made before the queue
+   fn withdraw_payout(
+      &self,
+      account: &AccountId,
+      coin_id: usize,
+      amount: u128,
+      request_id: Option<u64>,
+   ) -> Result<(u128, Option<u64>, Option<u64>), PoolError> {
+      let coin = &self.coins[coin_id].symbol;
+      let requests = self.withdraw_requests.get(account).unwrap_or_default();
+      let pending = |request: &&WithdrawRequest| {
+         request.status == WithdrawStatus::Pending && &request.coin == coin
+      };
+      let request = match request_id {
+         Some(id) => match requests.iter().filter(pending).find(|request| request.id == id) {
+            Some(request) => Some(request),
+            None => return Err(PoolError::NoSuchWithdrawRequest),
+         },
+         None => requests
+            .iter()
+            .filter(pending)
+            .find(|request| request.amount == amount)
+            .or_else(|| requests.iter().filter(pending).find(|request| request.amount <= amount)),
+      };
+      match request {
+         Some(request) if request.amount > amount => Err(PoolError::NotEnoughToFulfil),
+         Some(request) => Ok((request.amount, Some(request.id), request.position_id)),
+         None => {
+            let queued: u128 = requests.iter().filter(pending).map(|request| request.amount).sum();
+            let reserve = self.get_user_info(account)[coin_id].withdraw_reserve;
+            let unqueued = reserve - queued.min(reserve);
+            if unqueued == 0 {
+               return Err(PoolError::NotEnoughReserved);
+            }
+            Ok((unqueued.min(amount), None, None))
+         }
+      }
+   }
+
+   fn set_withdraw_status(&mut self, account: &AccountId, request_id: u64, status: WithdrawStatus) {
+      let mut requests = self.withdraw_requests.get(account).unwrap_or_default();
+      if let Some(request) = requests.iter_mut().find(|request| request.id == request_id) {
+         request.status = status;
+         self.withdraw_requests.insert(account, &requests);
+      }
+   }
+
+   // `amount` is in the token the coin's rewards are paid in, the budget is kept in coin units
+   fn fund_rewards(&mut self, coin_id: usize, amount: u128) {
+      // an exhausted budget stopped accrual, it picks up again from now
+      self.update_reward_index(coin_id);
+      let funded = mul_div(amount, REWARD_RATE_SCALE, self.paid_in(coin_id).1);
+      // what is committed already is covered first
+      let budget = &mut self.reward_budgets[coin_id];
+      let covered = funded.min(budget.shortfall);
+      budget.shortfall -= covered;
+      budget.funded += funded - covered;
+      PoolEvent::RewardFund(vec![RewardFundEvent {
+         coin: self.coins[coin_id].symbol.clone(),
+         amount: U128(amount),
+         funded: U128(self.reward_budgets[coin_id].funded),
+      }])
+      .emit();
+   }
+
+   fn default_reward_tiers() -> RewardTiers {
+      RewardTiers {
+         tiers: vec![],
+         qualified_multiplier: 10_000,
+         from_index: 0,
+         checkpoints: 0,
+      }
+   }
+
+   // the tiers a checkpoint at `index` counts towards. One on a config's from_index belongs to
+   // the config before, so it stays put when tiers change at that index
+   fn tiers_position(&self, coin_id: usize, index: u128) -> usize {
+      let history = &self.reward_tiers[coin_id];
+      history.partition_point(|config| config.from_index < index).saturating_sub(1)
+   }
+
+   // moves an account checkpoint between tiers. Leading tiers no checkpoint counts towards
+   // anymore can't be reached by any settlement, so they are dropped
+   fn move_checkpoint(&mut self, coin_id: usize, from: Option<u128>, to: Option<u128>) {
+      if let Some(index) = from {
+         let position = self.tiers_position(coin_id, index);
+         let config = &mut self.reward_tiers[coin_id][position];
+         config.checkpoints = config.checkpoints.saturating_sub(1);
+      }
+      if let Some(index) = to {
+         let position = self.tiers_position(coin_id, index);
+         self.reward_tiers[coin_id][position].checkpoints += 1;
+      }
+      let history = &mut self.reward_tiers[coin_id];
+      let unreachable = history.iter().take_while(|config| config.checkpoints == 0).count();
+      history.drain(..unreachable.min(history.len() - 1));
+   }
+
+   // drops the checkpoints of an account that is going away
+   fn remove_checkpoints(&mut self, reward_info: &[RewardInfo]) {
+      for (coin_id, info) in reward_info.iter().enumerate().take(self.coins.len()) {
+         if info.last_reward_time != 0 {
+            self.move_checkpoint(coin_id, Some(info.index), None);
+         }
+      }
+   }
+
+   // the balance as if it all earned the coin apr
+   fn weighted_balance(config: &RewardTiers, balance: u128, qualified: bool) -> u128 {
+      let mut weighted = if config.tiers.is_empty() { balance } else { 0 };
+      let mut lower = 0;
+      for tier in config.tiers.iter() {
+         let upper = tier.up_to.map_or(balance, |up_to| up_to.0.min(balance));
+         if upper > lower {
+            weighted += mul_div(upper - lower, tier.multiplier as u128, 10_000);
+            lower = upper;
+         }
+      }
+      if qualified {
+         weighted = mul_div(weighted, config.qualified_multiplier as u128, 10_000);
+      }
+      weighted
+   }
+
+   // the rate the principal earns at, REWARD_INDEX_SCALE being the coin apr. An empty
+   // principal gets the rate of its first unit
+   fn multiplier(config: &RewardTiers, principal: u128, qualified: bool) -> u128 {
+      if principal > 0 {
+         let weighted = Self::weighted_balance(config, principal, qualified);
+         return mul_div(weighted, REWARD_INDEX_SCALE, principal);
+      }
+      let multiplier = config.tiers.first().map_or(10_000, |tier| tier.multiplier) as u128;
+      let qualified_multiplier = if qualified { config.qualified_multiplier as u128 } else { 10_000 };
+      mul_div(REWARD_INDEX_SCALE, multiplier * qualified_multiplier, 100_000_000)
+   }
+
+   // what the account earned while the coin index moved from `from_index` to `to_index`,
+   // split across the tiers in effect on the way. Bands go by principal. Compounding balances
+   // grow continuously at the principal's rate, so settling more often pays nothing extra
+   fn account_reward(
+      &self,
+      coin_id: usize,
+      info: &UserInfo,
+      compounding: bool,
+      qualified: bool,
+      from_index: u128,
+      to_index: u128,
+   ) -> u128 {
+      let history = &self.reward_tiers[coin_id];
+      let mut reward = 0;
+      let mut exponent = 0;
+      let first = self.tiers_position(coin_id, from_index);
+      for (position, config) in history.iter().enumerate().skip(first) {
+         let start = from_index.max(config.from_index);
+         let end = history
+            .get(position + 1)
+            .map_or(to_index, |next| next.from_index.min(to_index));
+         if end > start && compounding {
+            let multiplier = Self::multiplier(config, info.amount, qualified);
+            exponent += accrued_reward(multiplier, end - start);
+         } else if end > start {
+            let weighted = Self::weighted_balance(config, info.amount, qualified);
+            reward += accrued_reward(weighted, end - start);
+         }
+      }
+      if compounding {
+         compounded_reward(info.amount + info.reward_amount, exponent)
+      } else {
+         reward
+      }
+   }
+
+   // what accrual reserved for `base` while the index moved from `from_index` to `to_index`,
+   // at the highest multiplier of each tiers in effect on the way
+   fn charged_cost(&self, coin_id: usize, base: u128, from_index: u128, to_index: u128) -> u128 {
+      let history = &self.reward_tiers[coin_id];
+      let mut exponent = 0;
+      let first = self.tiers_position(coin_id, from_index);
+      for (position, config) in history.iter().enumerate().skip(first) {
+         let start = from_index.max(config.from_index);
+         let end = history
+            .get(position + 1)
+            .map_or(to_index, |next| next.from_index.min(to_index));
+         if end > start {
+            exponent += accrued_reward(Self::tiers_max_multiplier(config), end - start);
+         }
+      }
+      compounded_cost(base, exponent)
+   }
+
+   fn effective_apr(&self, coin_id: usize, balance: u128, qualified: bool) -> u32 {
+      let config = match self.reward_tiers[coin_id].last() {
+         Some(config) => config,
+         None => return self.apr_at(coin_id, env::block_timestamp_ms()),
+      };
+      // an empty balance shows the rate of its first unit
+      let balance = balance.max(1);
+      let multiplier = match config.tiers.first() {
+         Some(tier) if tier.up_to.is_none_or(|up_to| up_to.0 >= balance) => {
+            tier.multiplier as u128
+         }
+         _ if config.tiers.is_empty() => 10_000,
+         _ => mul_div(Self::weighted_balance(config, balance, false), 10_000, balance),
+      };
+      let multiplier = if qualified {
+         multiplier * (config.qualified_multiplier as u128) / 10_000
+      } else {
+         multiplier
+      };
+      let apr = self.apr_at(coin_id, env::block_timestamp_ms()) as u128;
+      let apr = mul_div(apr, multiplier, 10_000);
+      u32::try_from(apr).unwrap_or_else(|_| PoolError::MathOverflow.panic())
+   }
+
+   // the rate the account's balance of a coin earns at
+   fn account_apr(&self, user_info: &[UserInfo], pot_info: &[PotInfo], coin_id: usize) -> u32 {
+      let qualified = pot_info[coin_id].qualified_amount > 0;
+      self.effective_apr(coin_id, user_info[coin_id].amount, qualified)
+   }
+
+   fn is_qualified(&self, account: &AccountId, coin_id: usize) -> bool {
+      self.get_pot_info_of(account)[coin_id].qualified_amount > 0
+   }
+
+   // moves what the balance earned since its checkpoint into reward_amount, the caller saves user_info
+   fn settle_reward(&mut self, account: &AccountId, user_info: &mut [UserInfo], coin_id: usize) {
+      self.update_reward_index(coin_id);
+      let index = self.reward_indexes[coin_id].index;
+      let mut reward_info = self.get_reward_info(account);
+      let qualified = self.is_qualified(account, coin_id);
+      let compounding = self.is_compounding(account, coin_id);
+      let checkpoint = reward_info[coin_id].index;
+      let reward = self.account_reward(
+         coin_id,
+         &user_info[coin_id],
+         compounding,
+         qualified,
+         checkpoint,
+         index,
+      );
+      // what accrual reserved for the account beyond its reward goes back to the budget
+      let base = user_info[coin_id].amount + user_info[coin_id].reward_amount;
+      let charged = self.charged_cost(coin_id, base, checkpoint, index);
+      let unsettled = &mut self.reward_indexes[coin_id].unsettled;
+      *unsettled = unsettled.saturating_sub(charged);
+      self.reward_budgets[coin_id].committed += reward;
+      // accounts that never settled only count while their balance earns, from before versioning
+      let counted = reward_info[coin_id].last_reward_time != 0 || base > 0;
+      self.move_checkpoint(coin_id, counted.then_some(checkpoint), Some(index));
+      let info = &mut user_info[coin_id];
+      reward_info[coin_id].index = index;
+      reward_info[coin_id].last_reward_time = env::block_timestamp_ms();
+
+      if reward > 0 {
+         let (amount, reward_token) = match &self.reward_tokens[coin_id] {
+            Some(reward_token) => {
+               let amount = mul_div(reward, reward_token.rate.0, REWARD_RATE_SCALE);
+               reward_info[coin_id].token_reward_amount += amount;
+               self.total_token_rewards[coin_id] += amount;
+               (amount, Some(reward_token.token_address.clone()))
+            }
+            None => {
+               info.reward_amount += reward;
+               self.total_rewards[coin_id] += reward;
+               self.reward_indexes[coin_id].total_base += reward;
+               (reward, None)
+            }
+         };
+         let mut positions = self.positions_of(account, coin_id, info);
+         Self::credit_positions(&mut positions, amount);
+         self.store_positions(account, coin_id, positions, info);
+         let mut credits = self.reward_credits.get(account).unwrap_or_default();
+         credits.push(RewardCredit {
+            coin_id: coin_id as u32,
+            amount,
+            paid_in_token: reward_token.is_some(),
+            time: env::block_timestamp_ms(),
+         });
+         if credits.len() > CREDIT_LIMIT {
+            credits.remove(0);
+         }
+         self.reward_credits.insert(account, &credits);
+         PoolEvent::Reward(vec![RewardEvent {
+            account: account.clone(),
+            coin: self.coins[coin_id].symbol.clone(),
+            amount: U128(amount),
+            reward_token,
+         }])
+         .emit();
+      }
+      self.reward_infos.insert(account, &reward_info);
+   }
+
+   // the account's positions in a coin. Principal no position holds yet, from before
+   // positions, shows as position 0 started at the old deposit_time
+   fn positions_of(&self, account: &AccountId, coin_id: usize, info: &UserInfo) -> Vec<Position> {
+      let positions = self.positions.get(account).unwrap_or_default();
+      let mut positions = positions.get(coin_id).cloned().unwrap_or_default();
+      let held: u128 = positions.iter().map(|position| position.amount.0).sum();
+      if held < info.amount {
+         positions.insert(
+            0,
+            Position {
+               id: 0,
+               amount: U128(info.amount - held),
+               start: info.deposit_time,
+               term: 0,
+               accrued: U128(0),
+            },
+         );
+      }
+      positions
+   }
+
+   // deposit_time follows the oldest position still open
+   fn store_positions(
+      &mut self,
+      account: &AccountId,
+      coin_id: usize,
+      coin_positions: Vec<Position>,
+      info: &mut UserInfo,
+   ) {
+      if let Some(oldest) = coin_positions.first() {
+         info.deposit_time = oldest.start;
+      }
+      let mut positions = self.positions.get(account).unwrap_or_default();
+      positions.resize(self.coins.len(), vec![]);
+      positions[coin_id] = coin_positions;
+      self.positions.insert(account, &positions);
+   }
+
+   // splits a settled reward over the positions by principal, the rounding goes to the newest
+   fn credit_positions(positions: &mut [Position], reward: u128) {
+      let total: u128 = positions.iter().map(|position| position.amount.0).sum();
+      if total == 0 {
+         return;
+      }
+      let mut left = reward;
+      for position in positions.iter_mut() {
+         let share = mul_div(reward, position.amount.0, total).min(left);
+         position.accrued.0 += share;
+         left -= share;
+      }
+      if let Some(newest) = positions.last_mut() {
+         newest.accrued.0 += left;
+      }
+   }
+
+   // takes `amount` of principal from the position `first`, then from the oldest ones,
+   // returning what came from each
+   fn take_positions(
+      positions: &mut Vec<Position>,
+      amount: u128,
+      first: Option<u64>,
+   ) -> Vec<Position> {
+      let first = first.and_then(|id| positions.iter().position(|position| position.id == id));
+      if let Some(index) = first {
+         let position = positions.remove(index);
+         positions.insert(0, position);
+      }
+      let mut left = amount;
+      let mut taken = vec![];
+      for position in positions.iter_mut() {
+         if left == 0 {
+            break;
+         }
+         let part = position.amount.0.min(left);
+         position.amount.0 -= part;
+         left -= part;
+         taken.push(Position {
+            amount: U128(part),
+            ..position.clone()
+         });
+      }
+      positions.retain(|position| position.amount.0 > 0);
+      positions.sort_by_key(|position| position.id);
+      taken
+   }
+
+   // the newest position a deposit can join, the balance from before positions aside
+   fn merge_target(positions: &[Position]) -> Option<usize> {
+      positions.iter().rposition(|position| position.id != 0)
+   }
+
+   // a withdrawal naming a position needs the account to hold it
+   fn check_position(
+      &self,
+      account: &AccountId,
+      coin_id: usize,
+      info: &UserInfo,
+      position_id: Option<u64>,
+   ) {
+      if let Some(position_id) = position_id {
+         let positions = self.positions_of(account, coin_id, info);
+         let found = positions.iter().any(|position| position.id == position_id);
+         ensure(found, PoolError::NoSuchPosition);
+      }
+   }
+
+   // hands taken principal back, reopening positions that were used up
+   fn restore_positions(positions: &mut Vec<Position>, taken: Vec<Position>) {
+      for part in taken {
+         match positions.iter_mut().find(|position| position.id == part.id) {
+            Some(position) => position.amount.0 += part.amount.0,
+            None => positions.push(part),
+         }
+      }
+      positions.sort_by_key(|position| position.id);
+   }
+
+   // rewards paid in a reward token leave the coin, so they have nothing to compound on
+   fn is_compounding(&self, account: &AccountId, coin_id: usize) -> bool {
+      !self.simple_interest.contains(account) && self.reward_tokens[coin_id].is_none()
+   }
+
+   // user and reward records with what was earned since the last settlement added in
+   fn get_rewards_now(&self, owner_id: &AccountId) -> (Vec<UserInfo>, Vec<RewardInfo>) {
+      let mut user_info = self.get_user_info(owner_id);
+      let mut reward_info = self.get_reward_info(owner_id);
+      let pot_info = self.get_pot_info_of(owner_id);
+      for (coin_id, info) in user_info.iter_mut().enumerate() {
+         let reward = self.account_reward(
+            coin_id,
+            info,
+            self.is_compounding(owner_id, coin_id),
+            pot_info[coin_id].qualified_amount > 0,
+            reward_info[coin_id].index,
+            self.reward_index_at(coin_id),
+         );
+         match &self.reward_tokens[coin_id] {
+            Some(reward_token) => {
+               reward_info[coin_id].token_reward_amount +=
+                  mul_div(reward, reward_token.rate.0, REWARD_RATE_SCALE)
+            }
+            None => info.reward_amount += reward,
+         }
+      }
+      (user_info, reward_info)
+   }
+
+   fn push_amount_history(&mut self, mut info: AmountInfo) {
+      info.amount.resize(self.coins.len(), 0);
+      info.reward.resize(self.coins.len(), 0);
+      self.amount_history.push(info);
+
+      if self.amount_history.len() > HISTORY_LIMIT {
+         self.amount_history.remove(0);
+      }
+   }
+
+   pub fn farm(&mut self, price: Vec<u128>) {
+      self.check_onlytreasury();
+      ensure(!self.paused.farm, PoolError::FarmPaused);
+      ensure(price.len() == self.coins.len(), PoolError::InvalidPriceCount);
+      let current_time = env::block_timestamp_ms();
+      let farm_starttime = self.farm_starttime;
+      let farm_endtime = farm_starttime + FARM_PERIOD;
+
+      //-----------------condition check------------------------------
+      if farm_starttime == 0 || current_time < farm_starttime {
+         PoolError::FarmNotStarted.panic();
+      }
+
+      let mut total_farm = self.total_farmed;
+
+      let farm_amount = FARM_AMOUNT * pow10(NEART_DECIMALS);
+
+      if farm_endtime < current_time || total_farm > farm_amount {
+         return;
+      }
+      //--------------------calc farming amount---------------------
+      let mut total_as_usd = 0;
+
+      let keys = self.user_infos.to_vec();
+      let accounts = keys.len() as u64;
+      for (key, _) in keys {
+         let user_info = self.get_user_info(&key); //(x/10^y) * (price / 10^2) * (10^decimals) /10^3 * 24 = x*price*24/(10^(decimals-y-5))
+         let mut farm = 0;
+
+         for (i, coin) in self.coins.iter().enumerate() {
+            let _price: u128 = price[i];
+            if !coin.paused.farm {
+               let value = mul_div(user_info[i].amount, mul(_price, 24), pow10(coin.decimals));
+               farm = add(farm, mul_div(value, pow10(NEART_DECIMALS), 100_000));
+            }
+            let usd = mul_div(user_info[i].amount, _price, pow10(coin.decimals)) / 100;
+            total_as_usd = add(total_as_usd, usd);
+         }
+
+         self.update_farm_info(key.clone(), farm);
+         total_farm = add(total_farm, farm);
+         if farm > 0 {
+            PoolEvent::Farm(vec![FarmEvent {
+               account: key,
+               amount: U128(farm),
+            }])
+            .emit();
+         }
+      }
+
+      PoolEvent::FarmRun(vec![FarmRunEvent {
+         accounts,
+         amount: U128(total_farm - self.total_farmed),
+         total_farmed: U128(total_farm),
+      }])
+      .emit();
+      self.total_farmed = total_farm;
+      //-------------------recalc token price ------------------------------------
+      //x * (price / 10^2) / 20,000,000
+      let multiple = total_as_usd / (20_000_000u128);
+      //0.18*(1.2)^multiple = 18/10^2 * (12) ^ multiple) /(10^multiple) *10^2
+      let multiple = u32::try_from(multiple).unwrap_or_else(|_| PoolError::MathOverflow.panic());
+      let price = scaled_pow(18, 12, 10, multiple);
+      if price != self.farm_price {
+         PoolEvent::FarmPrice(vec![FarmPriceEvent {
+            old_price: U128(self.farm_price),
+            new_price: U128(price),
+         }])
+         .emit();
+      }
+      self.farm_price = price;
+   }
+
+   pub fn pot_process(&mut self) {
+      self.check_onlytreasury();
+      ensure(!self.paused.pot, PoolError::PotPaused);
+
+      let keys = self.pot_infos.to_vec();
+      let accounts = keys.len() as u64;
+      let mut removed = 0;
+
+      for (key, mut pot_info) in keys {
+         let mut bnone = true;
+         let mut user_info = None;
+         for (coin_id, info) in pot_info.iter_mut().enumerate() {
+            if !self.coins[coin_id].paused.pot {
+               // the pot boost changes, settle under the old one first
+               if (info.qualified_amount > 0) != (info.amount > 0) {
+                  let user_info = user_info.get_or_insert_with(|| self.get_user_info(&key));
+                  self.settle_reward(&key, user_info, coin_id);
+               }
+               info.qualified_amount = info.amount;
+               info.amount = 0;
+            }
+            if info.qualified_amount != 0 || info.amount != 0 {
+               bnone = false;
+            }
+         }
+         if let Some(user_info) = user_info {
+            self.user_infos.insert(&key, &user_info);
+         }
+         if bnone {
+            self.pot_infos.remove(&key);
+            removed += 1;
+         } else {
+            self.pot_infos.insert(&key, &pot_info);
+         }
+      }
+      PoolEvent::PotRollover(vec![PotRolloverEvent { accounts, removed }]).emit();
+   }
+   pub fn get_pot_info(self) -> Vec<Vec<PotInfo>> {
+      let keys = self.pot_infos.to_vec();
+      let mut infos: Vec<Vec<PotInfo>> = vec![];
+      for (key, _) in keys {
+         infos.push(self.get_pot_info_of(&key))
+      }
+      infos
+   }
+   pub fn get_farm_info(self) -> Vec<FarmResult> {
+      let keys = self.user_infos.to_vec();
+      let mut infos: Vec<FarmResult> = vec![];
+
+      for (key, _) in keys {
+         let farminfo = match self.farm_infos.get(&key) {
+            Some(info) => info,
+            None => FarmInfo {
+               account: key.clone(),
+               amount: 0,
+            },
+         };
+         infos.push(FarmResult {
+            user_info: self.get_rewards_now(&key).0,
+            farm_info: farminfo,
+         })
+      }
+      infos
+   }
+   pub fn get_amount_history(self) -> Vec<AmountInfo> {
+      self.amount_history
+   }
+
+   // settled and pending rewards per coin, with the account's last reward credits
+   pub fn get_reward_statement(
+      &self,
+      account: AccountId,
+      from_index: u64,
+      limit: u64,
+   ) -> RewardStatement {
+      let user_info = self.get_user_info(&account);
+      let reward_info = self.get_reward_info(&account);
+      let (user_now, reward_now) = self.get_rewards_now(&account);
+      let pot_info = self.get_pot_info_of(&account);
+      let coins = self
+         .coins
+         .iter()
+         .enumerate()
+         .map(|(coin_id, coin)| CoinStatement {
+            coin: coin.symbol.clone(),
+            principal: user_info[coin_id].amount,
+            accrued: user_info[coin_id].reward_amount,
+            token_accrued: reward_info[coin_id].token_reward_amount,
+            apr: self.account_apr(&user_info, &pot_info, coin_id),
+            last_reward_time: reward_info[coin_id].last_reward_time,
+            pending: user_now[coin_id].reward_amount - user_info[coin_id].reward_amount,
+            token_pending: reward_now[coin_id].token_reward_amount
+               - reward_info[coin_id].token_reward_amount,
+         })
+         .collect();
+
+      let credits = self.reward_credits.get(&account).unwrap_or_default();
+      RewardStatement {
+         account,
+         coins,
+         total_credits: credits.len() as u64,
+         credits: credits.into_iter().skip(from_index as usize).take(limit as usize).collect(),
+      }
+   }
+
+   pub fn get_status(&self, account: AccountId) -> Status {
+      let (userinfo, rewardinfo) = self.get_rewards_now(&account);
+      let potinfo = self.get_pot_info_of(&account);
+      let effective_apr = (0..userinfo.len())
+         .map(|coin_id| self.account_apr(&userinfo, &potinfo, coin_id))
+         .collect();
+
+      let farminfo = match self.farm_infos.get(&account) {
+         Some(info) => info,
+         None => FarmInfo {
+            account: account.clone(),
+            amount: 0,
+         },
+      };
+
+      Status {
+         amount_history: self.amount_history.clone(),
+         user_info: userinfo,
+         reward_info: rewardinfo,
+         effective_apr,
+         compounding: !self.simple_interest.contains(&account),
+         farm_price: self.farm_price,
+         farm_info: farminfo,
+         farm_starttime: self.farm_starttime,
+         total_rewards: self.total_rewards.clone(),
+         total_token_rewards: self.total_token_rewards.clone(),
+         pot_info: potinfo,
+      }
+   }
+
+   // returns the unused amount to the token contract, so a failed forward refunds the depositor.
+   // Only what is still on the account is reversed and refunded, withdrawals made in the
+   // meantime were paid already
+   #[private]
+   pub fn on_deposit_forwarded(
+      &mut self,
+      account: AccountId,
+      coin: String,
+      amount: U128,
+      qualified: bool,
+      buffered: U128,
+   ) -> U128 {
+      if promise_succeeded() {
+         return U128(0);
+      }
+      let _amount: u128 = amount.into();
+      let coin_id = self.coin_id(&coin);
+      let forwarded = _amount - buffered.0;
+
+      let mut user_info = self.get_user_info(&account);
+      self.settle_reward(&account, &mut user_info, coin_id);
+      // the refund comes out of the forward that came back, then the buffer, which may have
+      // paid out the buffered share already. What is left of the forward joins the buffer
+      let buffer = &mut self.buffers[coin_id];
+      let removed = user_info[coin_id].amount.min(_amount).min(forwarded + buffer.balance);
+      buffer.balance = buffer.balance + forwarded - removed;
+      let mut positions = self.positions_of(&account, coin_id, &user_info[coin_id]);
+      let mut left = removed;
+      for position in positions.iter_mut().rev() {
+         let part = position.amount.0.min(left);
+         position.amount.0 -= part;
+         left -= part;
+      }
+      positions.retain(|position| position.amount.0 > 0);
+      self.store_positions(&account, coin_id, positions, &mut user_info[coin_id]);
+      user_info[coin_id].amount -= removed;
+      self.reward_indexes[coin_id].total_base -= removed;
+      self.user_infos.insert(&account, &user_info);
+
+      // pot_process may have moved the deposit out of its bucket in the meantime
+      let mut pot_info = self.get_pot_info_of(&account);
+      let info = &mut pot_info[coin_id];
+      let (first, second) = if qualified {
+         (&mut info.qualified_amount, &mut info.amount)
+      } else {
+         (&mut info.amount, &mut info.qualified_amount)
+      };
+      let taken = (*first).min(removed);
+      *first -= taken;
+      *second = second.saturating_sub(removed - taken);
+      self.pot_infos.insert(&account, &pot_info);
+
+      self.append_amount_history(coin.clone(), removed, false);
+
+      PoolEvent::DepositFailed(vec![AmountEvent {
+         account,
+         coin,
+         amount: U128(removed),
+      }])
+      .emit();
+      U128(removed)
+   }
+
+   // the claimed amount, or 0 when the transfer failed and the rewards went back to the account
+   #[private]
+   pub fn on_claim_complete(&mut self, account: AccountId, coin: String, amount: U128) -> U128 {
+      if promise_succeeded() {
+         return amount;
+      }
+      let _amount: u128 = amount.into();
+      let coin_id = self.coin_id(&coin);
+      let mut user_info = self.get_user_info(&account);
+      self.settle_reward(&account, &mut user_info, coin_id);
+      user_info[coin_id].reward_amount += _amount;
+      self.user_infos.insert(&account, &user_info);
+      self.total_rewards[coin_id] += _amount;
+      self.reward_indexes[coin_id].total_base += _amount;
+      self.append_amount_history(coin.clone(), 0, true);
+
+      PoolEvent::ClaimFailed(vec![AmountEvent {
+         account,
+         coin,
+         amount,
+      }])
+      .emit();
+      U128(0)
+   }
+
+   // the claimed amount in the reward token, or 0 when it went back to the account
+   #[private]
+   pub fn on_token_claim_complete(
+      &mut self,
+      account: AccountId,
+      coin: String,
+      amount: U128,
+   ) -> U128 {
+      if promise_succeeded() {
+         return amount;
+      }
+      let coin_id = self.coin_id(&coin);
+      let mut reward_info = self.get_reward_info(&account);
+      reward_info[coin_id].token_reward_amount += amount.0;
+      self.reward_infos.insert(&account, &reward_info);
+      self.total_token_rewards[coin_id] += amount.0;
+
+      PoolEvent::TokenClaimFailed(vec![AmountEvent {
+         account,
+         coin,
+         amount,
+      }])
+      .emit();
+      U128(0)
+   }
+
+   // the tokens handed back, or 0 when the transfer failed and they went back to the budget
+   #[private]
+   pub fn on_defund_complete(&mut self, coin: String, token: AccountId, tokens: U128) -> U128 {
+      if promise_succeeded() {
+         return tokens;
+      }
+      // a token the coin no longer pays rewards in has no budget to go back to
+      let coin_id = self.coin_id(&coin);
+      if self.paid_in(coin_id).0 == token {
+         self.fund_rewards(coin_id, tokens.0);
+      }
+      U128(0)
+   }
+
+   // returns the unused amount to the token contract: the surplus and the reward part, or
+   // the whole transfer when the payout failed, so the treasury only pays out principal
+   #[private]
+   pub fn on_withdraw_complete(&mut self, rollback: WithdrawRollback) -> U128 {
+      if promise_succeeded() {
+         // the reward part was paid out of the reward budget, the treasury only covers principal
+         return match rollback.source {
+            WithdrawSource::Buffer => U128(0),
+            _ => U128(rollback.surplus.0 + rollback.reward_amount.0),
+         };
+      }
+      let account = rollback.account.clone();
+      let coin_id = self.coin_id(&rollback.coin);
+      let amount: u128 = rollback.amount.into();
+      let reward_amount: u128 = rollback.reward_amount.into();
+      let pot_qualified_amount: u128 = rollback.pot_qualified_amount.into();
+      let pot_amount: u128 = rollback.pot_amount.into();
+      let farm_amount: u128 = rollback.farm_amount.into();
+      let principal = amount - reward_amount;
+
+      let mut user_info = self.get_user_info(&account);
+      self.settle_reward(&account, &mut user_info, coin_id);
+      let mut positions = self.positions_of(&account, coin_id, &user_info[coin_id]);
+      user_info[coin_id].amount += principal;
+      self.reward_indexes[coin_id].total_base += amount;
+      user_info[coin_id].reward_amount += reward_amount;
+      Self::restore_positions(&mut positions, rollback.positions);
+      self.store_positions(&account, coin_id, positions, &mut user_info[coin_id]);
+      // a failed buffer payout leaves the tokens on the contract, treasury payouts go back
+      // to waiting
+      match rollback.source {
+         WithdrawSource::Request(request_id) => {
+            user_info[coin_id].withdraw_reserve += amount;
+            self.set_withdraw_status(&account, request_id, WithdrawStatus::Pending);
+            self.pending_withdraws.insert(&request_id, &account);
+         }
+         WithdrawSource::Reserve => user_info[coin_id].withdraw_reserve += amount,
+         WithdrawSource::Buffer => self.buffers[coin_id].balance += principal,
+      }
+      self.user_infos.insert(&account, &user_info);
+      self.total_rewards[coin_id] += reward_amount;
+
+      if pot_qualified_amount + pot_amount > 0 {
+         let mut pot_info = self.get_pot_info_of(&account);
+         pot_info[coin_id].qualified_amount += pot_qualified_amount;
+         pot_info[coin_id].amount += pot_amount;
+         self.pot_infos.insert(&account, &pot_info);
+      }
+      if farm_amount > 0 {
+         self.update_farm_info(account.clone(), farm_amount);
+         self.total_farmed += farm_amount;
+      }
+
+      self.append_amount_history(rollback.coin.clone(), principal, true);
+
+      PoolEvent::WithdrawFailed(vec![AmountEvent {
+         account,
+         coin: rollback.coin,
+         amount: rollback.amount,
+      }])
+      .emit();
+      U128(rollback.amount.0 + rollback.surplus.0)
+   }
+}
+
+impl Check for Pool {
+   fn check_onlyowner(&self) {
+      ensure(self.owner == env::predecessor_account_id(), PoolError::NotAuthorized);
+   }
+   fn check_onlytreasury(&self) {
+      ensure(self.treasury == env::predecessor_account_id(), PoolError::OnlyTreasury);
+   }
+
+   fn deposit(
+      &mut self,
+      account: AccountId,
+      coin: String,
+      amount: u128,
+      qualified: bool,
+   ) -> PromiseOrValue<U128> {
+      let coin_id = self.coin_id(&coin);
+      ensure(self.coins[coin_id].enabled, PoolError::CoinDisabled);
+
+      let mut user_info = self.get_user_info(&account);
+      self.settle_reward(&account, &mut user_info, coin_id);
+      let mut positions = self.positions_of(&account, coin_id, &user_info[coin_id]);
+      let position_id = if positions.len() < POSITION_LIMIT {
+         self.next_position_id += 1;
+         positions.push(Position {
+            id: self.next_position_id,
+            amount: U128(amount),
+            start: env::block_timestamp_ms(),
+            term: 0,
+            accrued: U128(0),
+         });
+         self.next_position_id
+      } else {
+         // a full account tops up its newest position, which keeps its start
+         let position = match Self::merge_target(&positions) {
+            Some(index) => &mut positions[index],
+            None => PoolError::TooManyPositions.panic(),
+         };
+         position.amount.0 += amount;
+         position.id
+      };
+      user_info[coin_id].amount += amount;
+      self.reward_indexes[coin_id].total_base += amount;
+      self.store_positions(&account, coin_id, positions, &mut user_info[coin_id]);
+      self.user_infos.insert(&account, &user_info);
+
+      self.append_amount_history(coin.clone(), amount, true);
+      self.deposit_potinfo(account.clone(), coin.clone(), amount, qualified);
+      PoolEvent::Deposit(vec![DepositEvent {
+         account: account.clone(),
+         coin: coin.clone(),
+         amount: U128(amount),
+         qualified,
+         position_id,
+      }])
+      .emit();
+
+      // the buffer's share stays here, the rest goes to the treasury
+      let buffered = mul_div(amount, self.buffers[coin_id].rate as u128, 10_000);
+      self.buffers[coin_id].balance += buffered;
+      if buffered == amount {
+         return PromiseOrValue::Value(U128(0));
+      }
+      let forwarded = amount - buffered;
+
+      let arguments = json!({ "receiver_id": self.treasury.to_string(), "amount": forwarded.to_string() }) // method arguments
+         .to_string()
+         .into_bytes();
+      let callback = json!({
+         "account": account,
+         "coin": coin,
+         "amount": U128(amount),
+         "qualified": qualified,
+         "buffered": U128(buffered),
+      })
+      .to_string()
+      .into_bytes();
+      Promise::new(self.coins[coin_id].token_address.clone())
+         .function_call("ft_transfer".to_string(), arguments, 1, GAS_FOR_FT_TRANSFER)
+         .then(Promise::new(env::current_account_id()).function_call(
+            "on_deposit_forwarded".to_string(),
+            callback,
+            0,
+            GAS_FOR_RESOLVE,
+         ))
+         .into()
+   }
+
+   fn withdraw(
+      &mut self,
+      account: AccountId,
+      coin: String,
+      amount: u128,
+      price: Vec<u128>,
+      request_id: Option<u64>,
+   ) -> Promise {
+      let mut user_info = self.registered_user_info(&account);
+      let coin_id = self.coin_id(&coin);
+      user_info.resize(self.coins.len(), Self::empty_user_info(&account));
+      self.settle_reward(&account, &mut user_info, coin_id);
+
+      let (payout, request_id, position_id) = self
+         .withdraw_payout(&account, coin_id, amount, request_id)
+         .unwrap_or_else(|err| err.panic());
+      if let Some(request_id) = request_id {
+         self.set_withdraw_status(&account, request_id, WithdrawStatus::Fulfilled);
+         self.pending_withdraws.remove(&request_id);
+      }
+
+      user_info[coin_id].withdraw_reserve -= payout;
+      self.user_infos.insert(&account, &user_info);
+      let source = request_id.map_or(WithdrawSource::Reserve, WithdrawSource::Request);
+      let mut rollback = self.take_withdraw(account, coin, payout, price, position_id, source);
+      // the rest of the transfer goes back to the treasury
+      rollback.surplus = U128(amount - payout);
+      self.send_withdraw(rollback)
+   }
+   fn append_amount_history(&mut self, coin: String, amount: u128, b_add: bool) {
+      let coin_id = self.coin_id(&coin);
+      let mut info = match self.amount_history.last() {
+         Some(last) => last.clone(),
+         None => AmountInfo {
+            amount: vec![],
+            reward: vec![],
+            time: 0,
+         },
+      };
+      info.amount.resize(self.coins.len(), 0);
+      info.reward.resize(self.coins.len(), 0);
+
+      if b_add {
+         info.amount[coin_id] += amount;
+      } else {
+         info.amount[coin_id] -= amount;
+      }
+      info.time = env::block_timestamp_ms();
+      info.reward[coin_id] = self.total_rewards[coin_id];
+
+      self.push_amount_history(info);
+   }
+
+   fn deposit_potinfo(&mut self, account: AccountId, coin: String, amount: u128, qualified: bool) {
+      let mut pot_info = self.get_pot_info_of(&account);
+      let coin_id = self.coin_id(&coin);
+
+      if qualified {
+         pot_info[coin_id].qualified_amount += amount;
+      } else {
+         pot_info[coin_id].amount += amount;
+      }
+      self.pot_infos.insert(&account, &pot_info);
+   }
+   fn withdraw_potinfo(&mut self, account: AccountId, coin: String, amount: u128) -> (u128, u128) {
+      if self.pot_infos.get(&account).is_none() {
+         return (0, 0);
+      }
+      let coin_id = self.coin_id(&coin);
+      let mut pot_info = self.get_pot_info_of(&account);
+      let qualified_amount = pot_info[coin_id].qualified_amount.min(amount);
+      pot_info[coin_id].qualified_amount -= qualified_amount;
+      let _amount = pot_info[coin_id].amount.min(amount - qualified_amount);
+      pot_info[coin_id].amount -= _amount;
+      self.pot_infos.insert(&account, &pot_info);
+      (qualified_amount, _amount)
+   }
+   fn farm_withdraw(
+      &mut self,
+      account: AccountId,
+      coin: String,
+      amount: u128,
+      price: Vec<u128>,
+   ) -> u128 {
+      let current_time = env::block_timestamp_ms();
+      let farm_starttime = self.farm_starttime;
+      let farm_endtime = farm_starttime + FARM_PERIOD;
+
+      //-----------------condition check------------------------------
+      if farm_starttime == 0 || current_time < farm_starttime {
+         return 0;
+      }
+      if farm_endtime < current_time {
+         return 0;
+      }
+      //-----------------No farm yet------------------------
+      let mut farm_info = match self.farm_infos.get(&account) {
+         Some(info) => info,
+         None => return 0,
+      };
+
+      //--------------------calc farming amount---------------------
+      let coin_id = self.coin_id(&coin);
+      ensure(price.len() == self.coins.len(), PoolError::InvalidPriceCount);
+      let mut total_as_usd = U256::zero();
+      if self.user_infos.get(&account).is_some() {
+         let user_info = self.get_user_info(&account);
+         for (info, _price) in user_info.iter().zip(price.iter()) {
+            total_as_usd += wide_mul(info.amount, *_price);
+         }
+      }
+      if !total_as_usd.is_zero() {
+         let _price: u128 = price[coin_id];
+         let withdraw_as_usd = wide_mul(amount, _price).min(total_as_usd);
+         let withdraw_amount = wide_mul_div(withdraw_as_usd, farm_info.amount, total_as_usd)
+            .unwrap_or_else(|| PoolError::MathOverflow.panic());
+         farm_info.amount -= withdraw_amount;
+         self.total_farmed -= withdraw_amount;
+         self.farm_infos.insert(&account, &farm_info);
+         return withdraw_amount;
+      }
+      0
+   }
+
+   fn update_farm_info(&mut self, account: AccountId, amount: u128) {
+      let res = self.farm_infos.get(&account);
+      let user_info = match res {
+         Some(mut info) => {
+            info.amount = add(info.amount, amount);
+            info
+         }
+         None => FarmInfo {
+            account: account.clone(),
+            amount,
+         },
+      };
+      self.farm_infos.insert(&account, &user_info);
+   }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Pool {
+   fn ft_on_transfer(
+      &mut self,
+      sender_id: AccountId,
+      amount: U128,
+      msg: String,
+   ) -> PromiseOrValue<U128> {
+      log!(
+         "in {} tokens from @{} ft_on_transfer, msg = {}",
+         amount.0,
+         sender_id.as_ref(),
+         msg
+      );
+
+      // the coin is whatever token contract is calling, the msg can only confirm it
+      let token = env::predecessor_account_id();
+
+      //reward funding, kept on the contract. It may come in a reward token that is no coin
+      if sender_id == self.treasury {
+         if let Ok(FundParam { coin: msg_coin, reward_fund: true }) = serde_json::from_str(&msg) {
+            return match self.reward_fund_target(&token, &msg_coin) {
+               Some(coin_id) => {
+                  self.fund_rewards(coin_id, amount.into());
+                  PromiseOrValue::Value(U128(0))
+               }
+               None => {
+                  log!("@{} does not pay rewards of {:?}", token, msg_coin);
+                  PromiseOrValue::Value(amount)
+               }
+            };
+         }
+      }
+
+      let coin_id = match self.coin_id_by_token(&token) {
+         Some(coin_id) => coin_id,
+         None => return Self::refund(PoolError::UnknownToken, amount),
+      };
+      let coin = self.coins[coin_id].symbol.clone();
+
+      // sender_id is whoever called ft_transfer_call on the token, which may be a contract
+      if sender_id == self.treasury {
+         //withdraw
+         let param: WithdrawParam = match serde_json::from_str(&msg) {
+            Ok(param) => param,
+            Err(_) => return Self::refund(PoolError::InvalidMessage, amount),
+         };
+         if !Self::msg_coin_matches(&param.coin, &coin) {
+            return Self::refund(PoolError::CoinMismatch, amount);
+         }
+         if self.pause_flags(coin_id).withdraw {
+            return Self::refund(PoolError::WithdrawPaused, amount);
+         }
+         if self.user_infos.get(&param.account).is_none() {
+            return Self::refund(PoolError::AccountNotRegistered, amount);
+         }
+         if self.is_farming(&param.account) && param.price.len() != self.coins.len() {
+            return Self::refund(PoolError::InvalidPriceCount, amount);
+         }
+         if let Err(err) = self.withdraw_payout(&param.account, coin_id, amount.0, param.request_id) {
+            return Self::refund(err, amount);
+         }
+         let param_amount = amount.into();
+         Check::withdraw(self, param.account, coin, param_amount, param.price, param.request_id).into()
+      } else {
+         //deposit
+         let param: DepositParam = match serde_json::from_str(&msg) {
+            Ok(param) => param,
+            Err(_) => return Self::refund(PoolError::InvalidMessage, amount),
+         };
+         if !Self::msg_coin_matches(&param.coin, &coin) {
+            return Self::refund(PoolError::CoinMismatch, amount);
+         }
+         if !self.coins[coin_id].enabled {
+            return Self::refund(PoolError::CoinDisabled, amount);
+         }
+         if self.pause_flags(coin_id).deposit {
+            return Self::refund(PoolError::DepositPaused, amount);
+         }
+         if !self.is_storage_covered(&sender_id) {
+            return Self::refund(PoolError::StorageDepositRequired, amount);
+         }
+         if param.term.unwrap_or(0) != 0 {
+            return Self::refund(PoolError::TermNotSupported, amount);
+         }
+         self.deposit(sender_id, coin, amount.into(), param.qualified)
+      }
+   }
+}
+
+#[near_bindgen]
+impl StorageManagement for Pool {
+   // registered accounts may top up, which is needed once new coins grow their records
+   #[payable]
+   fn storage_deposit(
+      &mut self,
+      account_id: Option<AccountId>,
+      registration_only: Option<bool>,
+   ) -> StorageBalance {
+      let amount: Balance = env::attached_deposit();
+      let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+      let min_balance = self.storage_min_balance();
+      let balance = self.storage_deposits.get(&account_id);
+
+      let refund = match balance {
+         Some(_) if registration_only.unwrap_or(false) => {
+            log!("The account is already registered, refunding the deposit");
+            amount
+         }
+         Some(balance) => {
+            self.storage_deposits.insert(&account_id, &(balance + amount));
+            0
+         }
+         None => {
+            ensure(
+               amount >= min_balance,
+               PoolError::StorageDepositTooLow
+            );
+            if registration_only.unwrap_or(false) {
+               self.storage_deposits.insert(&account_id, &min_balance);
+               amount - min_balance
+            } else {
+               self.storage_deposits.insert(&account_id, &amount);
+               0
+            }
+         }
+      };
+      if refund > 0 {
+         Promise::new(env::predecessor_account_id()).transfer(refund);
+      }
+      self.storage_balance(&account_id)
+   }
+
+   #[payable]
+   fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+      assert_one_yocto();
+      let account_id = env::predecessor_account_id();
+      let storage_balance = match self.storage_balance_of(account_id.clone()) {
+         Some(storage_balance) => storage_balance,
+         None => PoolError::AccountNotRegistered.panic(),
+      };
+      let available: Balance = storage_balance.available.into();
+      let amount: Balance = amount.map(|amount| amount.into()).unwrap_or(available);
+      ensure(amount <= available, PoolError::StorageWithdrawTooLarge);
+      if amount > 0 {
+         let total: Balance = storage_balance.total.into();
+         self.storage_deposits.insert(&account_id, &(total - amount));
+         Promise::new(account_id.clone()).transfer(amount);
+      }
+      self.storage_balance(&account_id)
+   }
+
+   // deposits and rewards are never forfeited, `force` only gives up farmed credit
+   #[payable]
+   fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+      assert_one_yocto();
+      let account_id = env::predecessor_account_id();
+      let balance = match self.storage_deposits.get(&account_id) {
+         Some(balance) => balance,
+         None => {
+            log!("The account {} is not registered", &account_id);
+            return false;
+         }
+      };
+
+      let user_info = self.get_user_info(&account_id);
+      ensure(
+         user_info.iter().all(|info| info.amount == 0 && info.reward_amount == 0)
+            && self
+               .get_reward_info(&account_id)
+               .iter()
+               .all(|info| info.token_reward_amount == 0),
+         PoolError::AccountHasBalance
+      );
+      if let Some(farm_info) = self.farm_infos.get(&account_id) {
+         ensure(
+            farm_info.amount == 0 || force.unwrap_or(false),
+            PoolError::AccountHasFarm
+         );
+         self.total_farmed -= farm_info.amount;
+      }
+
+      self.user_infos.remove(&account_id);
+      self.remove_checkpoints(&self.get_reward_info(&account_id));
+      self.reward_infos.remove(&account_id);
+      self.simple_interest.remove(&account_id);
+      self.reward_credits.remove(&account_id);
+      for request in self.withdraw_requests.remove(&account_id).unwrap_or_default() {
+         self.pending_withdraws.remove(&request.id);
+      }
+      self.positions.remove(&account_id);
+      self.pot_infos.remove(&account_id);
+      self.farm_infos.remove(&account_id);
+      self.storage_deposits.remove(&account_id);
+      Promise::new(account_id).transfer(balance + 1);
+      true
+   }
+
+   fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+      StorageBalanceBounds {
+         min: self.storage_min_balance().into(),
+         max: None,
+      }
+   }
+
+   fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+      self.storage_deposits.get(&account_id)?;
+      Some(self.storage_balance(&account_id))
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use near_sdk::mock::VmAction;
+   use near_sdk::test_utils::{get_created_receipts, get_logs, VMContextBuilder};
+   use near_sdk::{testing_env, AccountId, RuntimeFeesConfig, VMConfig};
+
+   // part of writing unit tests is setting up a mock context
+   // provide a `predecessor` here, it'll modify the default context
+   fn get_context(predecessor: AccountId) -> VMContextBuilder {
+      let mut builder = VMContextBuilder::new();
+      builder.predecessor_account_id(predecessor);
+      builder
+   }
+
+   fn account(name: &str) -> AccountId {
+      AccountId::new_unchecked(name.to_string())
+   }
+
+   // arguments of the last callback scheduled to `method`
+   fn callback_args(method: &str) -> serde_json::Value {
+      get_created_receipts()
+         .iter()
+         .rev()
+         .flat_map(|receipt| receipt.actions.iter())
+         .find_map(|action| match action {
+            VmAction::FunctionCall { function_name, args, .. } if function_name == method => {
+               Some(serde_json::from_slice(args).unwrap())
+            }
+            _ => None,
+         })
+         .unwrap()
+   }
+
+   // resolves a callback against the given result of the promise it is chained to
+   fn callback_context(context: &mut VMContextBuilder, result: PromiseResult) {
+      testing_env!(
+         context.predecessor_account_id(env::current_account_id()).build(),
+         VMConfig::test(),
+         RuntimeFeesConfig::test(),
+         Default::default(),
+         vec![result]
+      );
+   }
+
+   // pays the minimum storage balance for `account`
+   fn register(context: &mut VMContextBuilder, pool: &mut Pool, account: &AccountId) {
+      let min_balance: Balance = pool.storage_balance_bounds().min.into();
+      testing_env!(context
+         .predecessor_account_id(account.clone())
+         .attached_deposit(min_balance)
+         .build());
+      pool.storage_deposit(None, Some(true));
+      testing_env!(context.attached_deposit(0).build());
+   }
+
+   // registers the coins the pool launched with, owner is the current account
+   fn setup_pool(context: &mut VMContextBuilder, treasury: AccountId) -> Pool {
+      let owner = env::current_account_id();
+      testing_env!(context
+         .predecessor_account_id(owner)
+         .block_timestamp(1_650_000_000_000_000_000)
+         .build());
+
+      let mut pool = Pool::new(None, treasury);
+      let coins = [
+         ("USDC", "usdc.testnet", 6, 2149),
+         ("USDT", "usdt.testnet", 6, 2149),
+         ("DAI", "dai.testnet", 18, 2149),
+         ("USN", "usn.testnet", 18, 2149),
+         ("wBTC", "wbtc.testnet", 8, 876),
+         ("ETH", "eth.testnet", 18, 876),
+         ("wNEAR", "wnear.testnet", 24, 1365),
+      ];
+      for (symbol, token, decimals, apr) in coins {
+         pool.add_coin(symbol.to_string(), account(token), decimals, apr);
+      }
+      register(context, &mut pool, &account("alice.testnet"));
+      testing_env!(context.predecessor_account_id(env::current_account_id()).build());
+      pool
+   }
+
+   #[test]
+   fn debug_get_hash() {
+      // Basic set up for a unit test
+      testing_env!(VMContextBuilder::new().build());
+
+      // Using a unit test to rapidly debug and iterate
+      let debug_solution = "near nomicon ref finance";
+      let debug_hash_bytes = env::sha256(debug_solution.as_bytes());
+      let debug_hash_string = hex::encode(debug_hash_bytes);
+      println!("Let's debug: {:?}", debug_hash_string);
+   }
+
+   #[test]
+   fn main_test() {
+      // Get Alice as an account ID
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      // Set up the testing context and unit test environment
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      fund(&mut context, &mut pool, "wbtc.testnet", 100_000_000);
+
+      testing_env!(context
+         .storage_usage(env::storage_usage())
+         .signer_account_id(alice.clone())
+         .predecessor_account_id(account("wbtc.testnet"))
+         .block_timestamp(1_650_000_000_000_000_000)
+         .build());
+
+      let val = DepositParam {
+         coin: Some("wBTC".to_string()),
+         qualified: true,
+         term: None,
+      };
+      let arguments = json!(val) // method arguments
+         .to_string();
+
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), arguments);
+
+      testing_env!(context
+         .storage_usage(env::storage_usage())
+         .predecessor_account_id(alice.clone())
+         .build());
+      pool.withdraw_reserve("wBTC".to_string(), U128::from(50_000_000), None);
+
+      testing_env!(context
+         .storage_usage(env::storage_usage())
+         .predecessor_account_id(treasury.clone())
+         .block_timestamp(1_650_001_000_000_000_000)
+         .build());
+      let price: Vec<u128> = vec![500000; 7];
+      let coin = "wBTC".to_string();
+      Check::withdraw(&mut pool, alice.clone(), coin, 50_000_000, price.clone(), None);
+
+      testing_env!(context
+         .storage_usage(env::storage_usage())
+         .predecessor_account_id(treasury)
+         .block_timestamp(1_650_002_000_000_000_000)
+         .build());
+      pool.farm(price);
+
+      let res = pool.get_status(alice);
+      println!("{:?}", res.user_info);
+      println!("{:?}", res.farm_info);
+      // 1000 seconds on the full deposit, then as long on what is left
+      let index_delta = 876 * 1_000_000 * REWARD_INDEX_SCALE / 10_000 / (YEAR as u128);
+      let reward = accrued_reward(100_000_000, index_delta);
+      let reward = reward + accrued_reward(50_000_000 + reward, index_delta);
+      assert_eq!(res.user_info[4].amount, 50_000_000);
+      assert_eq!(res.user_info[4].reward_amount, reward);
+   }
+
+   #[test]
+   fn farm_at_24_decimals() {
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+
+      // fifty thousand wNEAR at 100k dollars, far up the farm price curve
+      let amount = 50_000 * pow10(24);
+      testing_env!(context.predecessor_account_id(account("wnear.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128(amount), msg);
+      let mut price = vec![0; 7];
+      price[6] = 10_000_000;
+      testing_env!(context.predecessor_account_id(treasury).build());
+      pool.farm(price.clone());
+      let farm = 50_000 * 10_000_000 * 24 * 10;
+      let status = pool.get_status(alice.clone());
+      assert_eq!(status.farm_info.amount, farm);
+      assert_eq!(status.farm_price, 1_123_528_302_295_120_207_834);
+
+      // withdrawing half the balance takes half the farm
+      testing_env!(context.predecessor_account_id(alice.clone()).build());
+      pool.withdraw_reserve("wNEAR".to_string(), U128(amount / 2), None);
+      Check::withdraw(&mut pool, alice.clone(), "wNEAR".to_string(), amount / 2, price, None);
+      assert_eq!(pool.get_status(alice).farm_info.amount, farm / 2);
+   }
+
+   #[test]
+   #[should_panic(expected = "E048: Math overflow")]
+   fn farm_overflow_has_code() {
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      testing_env!(context.predecessor_account_id(account("wnear.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice, U128(pow10(24)), msg);
+
+      testing_env!(context.predecessor_account_id(treasury).build());
+      pool.farm(vec![u128::MAX / 10; 7]);
+   }
+
+   #[test]
+   fn coin_registry() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      pool.add_coin("USDC.e".to_string(), account("usdce.testnet"), 6, 1000);
+      pool.disable_coin("USDT".to_string());
+      assert_eq!(pool.get_coins().len(), 8);
+      assert!(!pool.get_coins()[1].enabled);
+
+      // existing records grow to the new registry size on read
+      let status = pool.get_status(alice);
+      assert_eq!(status.user_info.len(), 8);
+      assert_eq!(status.pot_info.len(), 8);
+      assert_eq!(status.total_rewards.len(), 8);
+   }
+
+   #[test]
+   #[should_panic(expected = "E051: Decimals above 38")]
+   fn add_coin_with_too_many_decimals() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.add_coin("BIG".to_string(), account("big.testnet"), 39, 1000);
+   }
+
+   #[test]
+   fn deposit_disabled_coin() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.disable_coin("USDT".to_string());
+
+      testing_env!(context
+         .signer_account_id(alice.clone())
+         .predecessor_account_id(account("usdt.testnet"))
+         .build());
+      let msg = json!({ "coin": "USDT", "qualified": false }).to_string();
+      let res = pool.ft_on_transfer(alice.clone(), U128::from(1_000_000), msg);
+      assert!(matches!(res, PromiseOrValue::Value(U128(1_000_000))));
+      assert_eq!(pool.get_status(alice).user_info[1].amount, 0);
+   }
+
+   #[test]
+   fn deposit_from_unknown_token() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      testing_env!(context
+         .signer_account_id(alice.clone())
+         .predecessor_account_id(account("fake-wbtc.testnet"))
+         .build());
+      let msg = json!({ "coin": "wBTC", "qualified": false }).to_string();
+      let res = pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+      assert!(matches!(res, PromiseOrValue::Value(U128(100_000_000))));
+      assert_eq!(pool.get_status(alice).user_info[4].amount, 0);
+   }
+
+   #[test]
+   fn deposit_coin_mismatch() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      testing_env!(context
+         .signer_account_id(alice.clone())
+         .predecessor_account_id(account("usdc.testnet"))
+         .build());
+      let msg = json!({ "coin": "wBTC", "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+      let status = pool.get_status(alice.clone());
+      assert_eq!(status.user_info[0].amount, 0);
+      assert_eq!(status.user_info[4].amount, 0);
+
+      // the coin field is optional, the token contract decides
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+      assert_eq!(pool.get_status(alice).user_info[0].amount, 100_000_000);
+   }
+
+   #[test]
+   fn deposit_through_contract() {
+      let alice = account("alice.testnet");
+      let dao = account("dao.sputnik.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      register(&mut context, &mut pool, &dao);
+
+      // alice signs a DAO proposal that transfers the DAO's wBTC into the pool
+      testing_env!(context
+         .signer_account_id(alice.clone())
+         .predecessor_account_id(account("wbtc.testnet"))
+         .build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(dao.clone(), U128::from(100_000_000), msg);
+      assert_eq!(pool.get_status(dao.clone()).user_info[4].amount, 100_000_000);
+      assert_eq!(pool.get_status(alice.clone()).user_info[4].amount, 0);
+
+      // the DAO reserves its own balance even though alice signed
+      testing_env!(context
+         .signer_account_id(alice)
+         .predecessor_account_id(dao.clone())
+         .build());
+      pool.withdraw_reserve("wBTC".to_string(), U128::from(40_000_000), None);
+      assert_eq!(pool.get_status(dao).user_info[4].withdraw_reserve, 40_000_000);
+   }
+
+   #[test]
+   fn treasury_identity_from_sender() {
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      let relayer = account("relayer.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+
+      // a treasury-signed transaction from another sender is just a deposit
+      testing_env!(context
+         .signer_account_id(treasury.clone())
+         .predecessor_account_id(account("wbtc.testnet"))
+         .build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+      assert_eq!(pool.get_status(alice.clone()).user_info[4].amount, 100_000_000);
+
+      testing_env!(context
+         .signer_account_id(alice.clone())
+         .predecessor_account_id(alice.clone())
+         .build());
+      pool.withdraw_reserve("wBTC".to_string(), U128::from(100_000_000), None);
+
+      // the treasury fulfils the withdrawal through a relayer
+      testing_env!(context
+         .signer_account_id(relayer)
+         .predecessor_account_id(account("wbtc.testnet"))
+         .build());
+      let msg = json!({ "account": alice, "price": vec![0; 7] }).to_string();
+      pool.ft_on_transfer(treasury, U128::from(100_000_000), msg);
+      assert_eq!(pool.get_status(alice).user_info[4].amount, 0);
+   }
+
+   #[test]
+   fn failed_deposit_forward_rolls_back() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "qualified": true }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+      let args = callback_args("on_deposit_forwarded");
+
+      callback_context(&mut context, PromiseResult::Failed);
+      let unused = pool.on_deposit_forwarded(
+         alice.clone(),
+         "wBTC".to_string(),
+         serde_json::from_value(args["amount"].clone()).unwrap(),
+         args["qualified"].as_bool().unwrap(),
+         serde_json::from_value(args["buffered"].clone()).unwrap(),
+      );
+      assert_eq!(unused.0, 100_000_000);
+
+      let status = pool.get_status(alice);
+      assert_eq!(status.user_info[4].amount, 0);
+      assert_eq!(status.pot_info[4].qualified_amount, 0);
+      assert_eq!(status.amount_history.last().unwrap().amount[4], 0);
+   }
+
+   #[test]
+   fn failed_deposit_forward_after_withdrawal() {
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "qualified": true }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+      let args = callback_args("on_deposit_forwarded");
+
+      // the treasury pays out part of the deposit before the forward resolves
+      testing_env!(context.predecessor_account_id(alice.clone()).build());
+      pool.withdraw_reserve("wBTC".to_string(), U128::from(60_000_000), None);
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "account": alice, "price": vec![0; 7] }).to_string();
+      pool.ft_on_transfer(treasury, U128::from(60_000_000), msg);
+
+      callback_context(&mut context, PromiseResult::Failed);
+      let unused = pool.on_deposit_forwarded(
+         alice.clone(),
+         "wBTC".to_string(),
+         serde_json::from_value(args["amount"].clone()).unwrap(),
+         args["qualified"].as_bool().unwrap(),
+         serde_json::from_value(args["buffered"].clone()).unwrap(),
+      );
+      // only what is left on the account goes back, the rest of the forward stays as buffer
+      assert_eq!(unused.0, 40_000_000);
+      assert_eq!(pool.get_liquidity_buffer("wBTC".to_string()).balance, 60_000_000);
+
+      let status = pool.get_status(alice.clone());
+      assert_eq!(status.user_info[4].amount, 0);
+      assert_eq!(status.pot_info[4].qualified_amount, 0);
+      assert_eq!(status.amount_history.last().unwrap().amount[4], 0);
+      assert!(pool.get_positions(alice, "wBTC".to_string()).is_empty());
+   }
+
+   #[test]
+   fn failed_deposit_forward_after_buffer_payout() {
+      let alice = account("alice.testnet");
+      let bob = account("bob.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.set_buffer_rate("wBTC".to_string(), 2_000);
+      register(&mut context, &mut pool, &bob);
+      let msg = json!({ "qualified": false }).to_string();
+      for user in [&bob, &alice] {
+         testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+         pool.ft_on_transfer(user.clone(), U128::from(100_000_000), msg.clone());
+      }
+      let args = callback_args("on_deposit_forwarded");
+
+      // alice takes bob's buffered share along with her own before her forward fails
+      testing_env!(context.predecessor_account_id(alice.clone()).attached_deposit(1).build());
+      pool.withdraw("wBTC".to_string(), U128::from(40_000_000), None);
+      assert_eq!(pool.get_liquidity_buffer("wBTC".to_string()).balance, 0);
+
+      callback_context(&mut context, PromiseResult::Failed);
+      let unused = pool.on_deposit_forwarded(
+         alice.clone(),
+         "wBTC".to_string(),
+         serde_json::from_value(args["amount"].clone()).unwrap(),
+         args["qualified"].as_bool().unwrap(),
+         serde_json::from_value(args["buffered"].clone()).unwrap(),
+      );
+      // 40 paid and 60 refunded make her 100, bob's share is back in the buffer
+      assert_eq!(unused.0, 60_000_000);
+      assert_eq!(pool.get_liquidity_buffer("wBTC".to_string()).balance, 20_000_000);
+      assert_eq!(pool.get_status(alice).user_info[4].amount, 0);
+   }
+
+   #[test]
+   fn failed_withdraw_payout_rolls_back() {
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "qualified": true }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+
+      testing_env!(context.predecessor_account_id(alice.clone()).build());
+      pool.withdraw_reserve("wBTC".to_string(), U128::from(60_000_000), None);
+
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "account": alice, "price": vec![0; 7] }).to_string();
+      pool.ft_on_transfer(treasury, U128::from(60_000_000), msg);
+      assert_eq!(pool.get_status(alice.clone()).user_info[4].amount, 40_000_000);
+      let args = callback_args("on_withdraw_complete");
+
+      callback_context(&mut context, PromiseResult::Failed);
+      let unused = pool.on_withdraw_complete(serde_json::from_value(args["rollback"].clone()).unwrap());
+      assert_eq!(unused.0, 60_000_000);
+
+      let status = pool.get_status(alice);
+      assert_eq!(status.user_info[4].amount, 100_000_000);
+      assert_eq!(status.user_info[4].withdraw_reserve, 60_000_000);
+      assert_eq!(status.pot_info[4].qualified_amount, 100_000_000);
+      assert_eq!(status.amount_history.last().unwrap().amount[4], 100_000_000);
+      let pending = pool.get_pending_withdraws(0, 10);
+      assert_eq!(pending.len(), 1);
+      assert_eq!(pending[0].status, WithdrawStatus::Pending);
+   }
+
+   #[test]
+   fn withdraw_queue() {
+      let alice = account("alice.testnet");
+      let bob = account("bob.testnet");
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      register(&mut context, &mut pool, &bob);
+      for user in [&alice, &bob] {
+         testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+         let msg = json!({ "qualified": false }).to_string();
+         pool.ft_on_transfer(user.clone(), U128::from(100_000_000), msg);
+      }
+
+      // requests add up and can't exceed the balance
+      testing_env!(context.predecessor_account_id(alice.clone()).build());
+      let first = pool.withdraw_reserve("wBTC".to_string(), U128::from(30_000_000), None);
+      let second = pool.withdraw_reserve("wBTC".to_string(), U128::from(50_000_000), None);
+      testing_env!(context.predecessor_account_id(bob.clone()).build());
+      let third = pool.withdraw_reserve("wBTC".to_string(), U128::from(20_000_000), None);
+      assert_eq!(pool.get_status(alice.clone()).user_info[4].withdraw_reserve, 80_000_000);
+      let pending = pool.get_pending_withdraws(second, 10);
+      let ids: Vec<u64> = pending.iter().map(|request| request.id).collect();
+      assert_eq!(ids, vec![second, third]);
+
+      // a cancelled request leaves the queue and frees the reserve
+      testing_env!(context.predecessor_account_id(alice.clone()).build());
+      pool.cancel_withdraw(first);
+      assert_eq!(pool.get_status(alice.clone()).user_info[4].withdraw_reserve, 50_000_000);
+      assert_eq!(pool.get_withdraw_requests(alice.clone())[0].status, WithdrawStatus::Cancelled);
+      assert_eq!(pool.get_pending_withdraws(0, 10)[0].id, second);
+      let page = pool.get_pending_withdraws(second + 1, 1);
+      assert_eq!(page.iter().map(|request| request.id).collect::<Vec<_>>(), vec![third]);
+
+      // the treasury fulfils bob's request by id before alice's
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "account": bob, "price": vec![0; 7], "request_id": third }).to_string();
+      pool.ft_on_transfer(treasury.clone(), U128::from(20_000_000), msg);
+      assert_eq!(pool.get_status(bob.clone()).user_info[4].amount, 80_000_000);
+      assert_eq!(pool.get_withdraw_requests(bob)[0].status, WithdrawStatus::Fulfilled);
+
+      // without an id the matching request is fulfilled
+      let msg = json!({ "account": alice, "price": vec![0; 7] }).to_string();
+      pool.ft_on_transfer(treasury, U128::from(50_000_000), msg);
+      let status = pool.get_status(alice.clone());
+      assert_eq!(status.user_info[4].amount, 50_000_000);
+      assert_eq!(status.user_info[4].withdraw_reserve, 0);
+      assert_eq!(pool.get_withdraw_requests(alice)[1].status, WithdrawStatus::Fulfilled);
+      assert!(pool.get_pending_withdraws(0, 10).is_empty());
+   }
+
+   #[test]
+   fn deposit_positions() {
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      fund(&mut context, &mut pool, "usdc.testnet", 1_000_000_000_000);
+
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(1_000_000_000), msg.clone());
+
+      // topping up opens a second position and keeps the first one's clock
+      let top_up_time = 1_650_000_000_000_000_000 + HALF_YEAR_NS;
+      testing_env!(context.block_timestamp(top_up_time).build());
+      pool.ft_on_transfer(alice.clone(), U128::from(1_000_000_000), msg);
+      let positions = pool.get_positions(alice.clone(), "USDC".to_string());
+      assert_eq!(positions.len(), 2);
+      assert_eq!(positions[0].start, 1_650_000_000_000);
+      assert_eq!(positions[1].start, top_up_time / 1_000_000);
+      assert_eq!(positions[0].accrued.0, HALF_YEAR_REWARD);
+      assert_eq!(positions[1].accrued.0, 0);
+      assert_eq!(pool.get_status(alice.clone()).user_info[0].deposit_time, 1_650_000_000_000);
+
+      // a withdrawal naming the newer position takes it before the older one
+      testing_env!(context.predecessor_account_id(alice.clone()).build());
+      let second = positions[1].id;
+      pool.withdraw_reserve("USDC".to_string(), U128::from(1_200_000_000), Some(second));
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "account": alice, "price": vec![0; 7] }).to_string();
+      pool.ft_on_transfer(treasury, U128::from(1_200_000_000), msg);
+      let positions = pool.get_positions(alice.clone(), "USDC".to_string());
+      assert_eq!(positions.len(), 1);
+      assert_eq!((positions[0].id, positions[0].amount.0), (1, 800_000_000));
+
+      // a failed payout reopens the position it used up
+      let args = callback_args("on_withdraw_complete");
+      callback_context(&mut context, PromiseResult::Failed);
+      pool.on_withdraw_complete(serde_json::from_value(args["rollback"].clone()).unwrap());
+      let positions = pool.get_positions(alice, "USDC".to_string());
+      let amounts: Vec<u128> = positions.iter().map(|position| position.amount.0).collect();
+      assert_eq!(amounts, vec![1_000_000_000, 1_000_000_000]);
+      assert_eq!(positions[1].id, second);
+   }
+
+   #[test]
+   fn full_positions_merge() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      let msg = json!({ "qualified": false }).to_string();
+      for _ in 0..POSITION_LIMIT {
+         testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+         pool.ft_on_transfer(alice.clone(), U128(1_000_000), msg.clone());
+      }
+
+      // the eleventh deposit joins the newest position
+      let time = 1_650_000_000_000_000_000 + HALF_YEAR_NS;
+      testing_env!(context.block_timestamp(time).build());
+      pool.ft_on_transfer(alice.clone(), U128(1_000_000), msg.clone());
+      let positions = pool.get_positions(alice.clone(), "USDC".to_string());
+      assert_eq!(positions.len(), POSITION_LIMIT);
+      let newest = positions.last().unwrap();
+      assert_eq!((newest.amount.0, newest.start), (2_000_000, 1_650_000_000_000));
+
+   }
+
+   #[test]
+   fn term_deposit_refunded() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "qualified": false, "term": 86_400_000 }).to_string();
+      let res = pool.ft_on_transfer(alice.clone(), U128(1_000_000), msg);
+      assert!(matches!(res, PromiseOrValue::Value(U128(1_000_000))));
+      assert_eq!(pool.get_status(alice.clone()).user_info[0].amount, 0);
+
+      // a zero term is the plain deposit
+      let msg = json!({ "qualified": false, "term": 0 }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128(1_000_000), msg);
+      assert_eq!(pool.get_status(alice).user_info[0].amount, 1_000_000);
+   }
+
+   #[test]
+   fn treasury_surplus_refunded() {
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+      testing_env!(context.predecessor_account_id(alice.clone()).build());
+      let request_id = pool.withdraw_reserve("wBTC".to_string(), U128::from(60_000_000), None);
+
+      // the treasury sends 70 for a request of 60, 10 go back whatever the payout does
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let price = vec![0; 7];
+      let msg = json!({ "account": alice, "price": price, "request_id": request_id }).to_string();
+      pool.ft_on_transfer(treasury, U128::from(70_000_000), msg);
+      assert_eq!(pool.get_status(alice).user_info[4].amount, 40_000_000);
+      assert_eq!(callback_args("ft_transfer")["amount"], json!("60000000"));
+      let args = callback_args("on_withdraw_complete");
+      let rollback: WithdrawRollback = serde_json::from_value(args["rollback"].clone()).unwrap();
+      assert_eq!(rollback.surplus.0, 10_000_000);
+
+      callback_context(&mut context, PromiseResult::Successful(vec![]));
+      assert_eq!(pool.on_withdraw_complete(rollback.clone()).0, 10_000_000);
+      callback_context(&mut context, PromiseResult::Failed);
+      assert_eq!(pool.on_withdraw_complete(rollback).0, 70_000_000);
+   }
+
+   #[test]
+   fn treasury_transfer_errors_refunded() {
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+      testing_env!(context.predecessor_account_id(alice.clone()).build());
+      let request_id = pool.withdraw_reserve("wBTC".to_string(), U128::from(60_000_000), None);
+
+      let price = vec![0; 7];
+      let refunded = |pool: &mut Pool, context: &mut VMContextBuilder, msg: String| {
+         testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+         let res = pool.ft_on_transfer(treasury.clone(), U128::from(50_000_000), msg);
+         matches!(res, PromiseOrValue::Value(U128(50_000_000)))
+      };
+      // short of the request
+      let msg = json!({ "account": alice, "price": price, "request_id": request_id });
+      assert!(refunded(&mut pool, &mut context, msg.to_string()));
+      // no such request
+      let msg = json!({ "account": alice, "price": price, "request_id": request_id + 1 });
+      assert!(refunded(&mut pool, &mut context, msg.to_string()));
+      // no request it covers and nothing reserved outside the queue
+      let msg = json!({ "account": alice, "price": price });
+      assert!(refunded(&mut pool, &mut context, msg.to_string()));
+      // a farming account needs a price per coin
+      testing_env!(context.predecessor_account_id(treasury.clone()).build());
+      pool.farm(vec![1; 7]);
+      let msg = json!({ "account": alice, "price": [0], "request_id": request_id });
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let res = pool.ft_on_transfer(treasury, U128::from(60_000_000), msg.to_string());
+      assert!(matches!(res, PromiseOrValue::Value(U128(60_000_000))));
+      assert_eq!(get_logs().pop().unwrap(), "E043: Invalid price count");
+
+      let status = pool.get_status(alice.clone());
+      assert_eq!(status.user_info[4].withdraw_reserve, 60_000_000);
+      assert_eq!(pool.get_pending_withdraws(0, 10)[0].id, request_id);
+   }
+
+   #[test]
+   fn malformed_msg_refunds() {
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let unused = |result: PromiseOrValue<U128>| match result {
+         PromiseOrValue::Value(amount) => amount.0,
+         PromiseOrValue::Promise(_) => panic!("expected a refund"),
+      };
+      assert_eq!(unused(pool.ft_on_transfer(alice.clone(), U128(100), "deposit".to_string())), 100);
+      assert_eq!(unused(pool.ft_on_transfer(treasury.clone(), U128(100), "{}".to_string())), 100);
+      let msg = json!({ "account": "bob.testnet", "price": vec![0; 7] }).to_string();
+      assert_eq!(unused(pool.ft_on_transfer(treasury, U128(100), msg)), 100);
+      let logs = get_logs();
+      assert_eq!(logs.iter().filter(|log| log.as_str() == "E011: Malformed msg").count(), 2);
+      assert!(logs.iter().any(|log| log == "E012: Account is not registered"));
+      assert_eq!(pool.get_status(alice).user_info[4].amount, 0);
+   }
+
+   #[test]
+   #[should_panic(expected = "E005: Not correct coin type")]
+   fn unknown_coin_has_code() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice);
+      let pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.get_liquidity_buffer("DOGE".to_string());
+   }
+
+   #[test]
+   fn self_service_withdraw() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.set_buffer_rate("wBTC".to_string(), 2_000);
+
+      // a fifth of the deposit stays on the contract
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+      assert_eq!(pool.get_liquidity_buffer("wBTC".to_string()).balance, 20_000_000);
+      assert_eq!(callback_args("ft_transfer")["amount"], json!("80000000"));
+
+      // what the buffer covers is paid at once
+      testing_env!(context.predecessor_account_id(alice.clone()).attached_deposit(1).build());
+      assert_eq!(pool.withdraw("wBTC".to_string(), U128::from(15_000_000), None), None);
+      assert_eq!(pool.get_status(alice.clone()).user_info[4].amount, 85_000_000);
+      assert_eq!(pool.get_liquidity_buffer("wBTC".to_string()).balance, 5_000_000);
+      let args = callback_args("on_withdraw_complete");
+      assert_eq!(args["rollback"]["source"], json!("buffer"));
+
+      // more than that goes to the treasury queue
+      let request_id = pool.withdraw("wBTC".to_string(), U128::from(10_000_000), None);
+      let pending = pool.get_pending_withdraws(0, 10);
+      assert_eq!(request_id, Some(pending[0].id));
+      assert_eq!(pool.get_status(alice.clone()).user_info[4].withdraw_reserve, 10_000_000);
+
+      // a failed buffer payout puts the tokens back in the buffer, not in the queue
+      callback_context(&mut context, PromiseResult::Failed);
+      pool.on_withdraw_complete(serde_json::from_value(args["rollback"].clone()).unwrap());
+      assert_eq!(pool.get_liquidity_buffer("wBTC".to_string()).balance, 20_000_000);
+      let status = pool.get_status(alice);
+      assert_eq!(status.user_info[4].amount, 100_000_000);
+      assert_eq!(status.user_info[4].withdraw_reserve, 10_000_000);
+   }
+
+   #[test]
+   #[should_panic(expected = "E031: No such position")]
+   fn buffer_withdraw_checks_position() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.set_buffer_rate("wBTC".to_string(), 2_000);
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+
+      testing_env!(context.predecessor_account_id(alice).attached_deposit(1).build());
+      pool.withdraw("wBTC".to_string(), U128::from(15_000_000), Some(99));
+   }
+
+   #[test]
+   #[should_panic(expected = "Not enough balance")]
+   fn withdraw_requests_limited_by_balance() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+
+      testing_env!(context.predecessor_account_id(alice).build());
+      pool.withdraw_reserve("wBTC".to_string(), U128::from(60_000_000), None);
+      pool.withdraw_reserve("wBTC".to_string(), U128::from(60_000_000), None);
+   }
+
+   #[test]
+   fn successful_transfer_keeps_state() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      testing_env!(context.predecessor_account_id(account("wbtc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), msg);
+
+      callback_context(&mut context, PromiseResult::Successful(vec![]));
+      let coin = "wBTC".to_string();
+      let unused = pool.on_deposit_forwarded(alice.clone(), coin, U128(100_000_000), false, U128(0));
+      assert_eq!(unused.0, 0);
+      assert_eq!(pool.get_status(alice).user_info[4].amount, 100_000_000);
+   }
+
+   #[test]
+   fn deposit_requires_storage() {
+      let bob = account("bob.testnet");
+      let mut context = get_context(bob.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      assert!(pool.storage_balance_of(bob.clone()).is_none());
+
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      let res = pool.ft_on_transfer(bob.clone(), U128::from(1_000_000), msg.clone());
+      assert!(matches!(res, PromiseOrValue::Value(U128(1_000_000))));
+      assert_eq!(pool.get_status(bob.clone()).user_info[0].amount, 0);
+
+      register(&mut context, &mut pool, &bob);
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      pool.ft_on_transfer(bob.clone(), U128::from(1_000_000), msg.clone());
+      assert_eq!(pool.get_status(bob.clone()).user_info[0].amount, 1_000_000);
+
+      // a new coin grows every record, so the old registration has to be topped up
+      testing_env!(context.predecessor_account_id(env::current_account_id()).build());
+      pool.add_coin("USDC.e".to_string(), account("usdce.testnet"), 6, 1000);
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let res = pool.ft_on_transfer(bob.clone(), U128::from(1_000_000), msg);
+      assert!(matches!(res, PromiseOrValue::Value(U128(1_000_000))));
+
+      let min_balance: Balance = pool.storage_balance_bounds().min.into();
+      let total: Balance = pool.storage_balance_of(bob.clone()).unwrap().total.into();
+      testing_env!(context
+         .predecessor_account_id(bob.clone())
+         .attached_deposit(min_balance - total)
+         .build());
+      pool.storage_deposit(None, None);
+      assert_eq!(pool.storage_balance_of(bob).unwrap().available.0, 0);
+   }
+
+   #[test]
+   #[should_panic(expected = "Can't unregister the account with the positive balance")]
+   fn unregister_with_balance() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(1_000_000), msg);
+
+      testing_env!(context.predecessor_account_id(alice).attached_deposit(1).build());
+      pool.storage_unregister(Some(true));
+   }
+
+   #[test]
+   fn deposit_emits_event() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "qualified": true }).to_string();
+      pool.ft_on_transfer(alice, U128::from(1_000_000), msg);
+
+      let event = get_logs()
+         .into_iter()
+         .find_map(|log| log.strip_prefix("EVENT_JSON:").map(str::to_string))
+         .unwrap();
+      assert_eq!(
+         serde_json::from_str::<serde_json::Value>(&event).unwrap(),
+         json!({
+            "standard": "pool",
+            "version": "1.0.0",
+            "event": "deposit",
+            "data": [{
+               "account": "alice.testnet",
+               "coin": "USDC",
+               "amount": "1000000",
+               "qualified": true,
+               "position_id": 1
+            }]
+         })
+      );
+   }
+
+   #[test]
+   #[should_panic(expected = "Not Authorized")]
+   fn delete_all_not_owner() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.set_paused(None, PauseFlags { deposit: true, ..Default::default() });
+
+      testing_env!(context.predecessor_account_id(alice).build());
+      pool.delete_all(100);
+   }
+
+   #[test]
+   #[should_panic(expected = "Not Authorized")]
+   fn set_apr_not_owner() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      testing_env!(context.predecessor_account_id(alice).build());
+      pool.set_apr("USDC".to_string(), 100_000, None);
+   }
+
+   #[test]
+   #[should_panic(expected = "Pool is not paused")]
+   fn delete_all_not_paused() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice);
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.delete_all(100);
+   }
+
+   #[test]
+   #[should_panic(expected = "Users still have deposits")]
+   fn delete_all_with_deposits() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice, U128::from(1_000_000), msg);
+
+      testing_env!(context.predecessor_account_id(env::current_account_id()).build());
+      pool.set_paused(None, PauseFlags { deposit: true, ..Default::default() });
+      pool.delete_all(100);
+   }
+
+   #[test]
+   fn delete_all_paginated() {
+      let alice = account("alice.testnet");
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(alice.clone(), U128::from(1_000_000), msg);
+
+      testing_env!(context.predecessor_account_id(alice.clone()).build());
+      pool.withdraw_reserve("USDC".to_string(), U128::from(1_000_000), None);
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "account": alice, "price": vec![0; 7] }).to_string();
+      pool.ft_on_transfer(treasury, U128::from(1_000_000), msg);
+
+      testing_env!(context.predecessor_account_id(env::current_account_id()).build());
+      pool.set_paused(None, PauseFlags { deposit: true, ..Default::default() });
+      // one user record and one pot record
+      assert_eq!(pool.delete_all(1), 1);
+      assert_eq!(pool.delete_all(1), 0);
+      assert!(pool.get_amount_history().is_empty());
+   }
+
+   #[test]
+   fn migrate_from_v1() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(env::current_account_id());
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000).build());
+
+      let wnear = account("ft.alenzertest.testnet");
+      let mut old = PoolV1 {
+         owner: env::current_account_id(),
+         treasury: account("treasury.testnet"),
+         apr: vec![2149, 2149, 2149, 2149, 876, 876, 1365],
+         user_infos: UnorderedMap::new(b"n"),
+         total_rewards: vec![0, 0, 0, 0, 5, 0, 0],
+         amount_history: vec![],
+         farm_starttime: 1_650_000_000_000,
+         farm_price: 18,
+         farm_infos: UnorderedMap::new(b"f"),
+         total_farmed: 0,
+         pot_infos: UnorderedMap::new(b"p"),
+         token_address: vec![wnear.clone(); 7],
       };
+      let mut user_info = vec![Pool::empty_user_info(&alice); 7];
+      user_info[4].amount = 100;
+      user_info[4].reward_amount = 5;
+      old.user_infos.insert(&alice, &user_info);
+      env::state_write(&old);
 
-      if qualified {
-         pot_info[getcoin_id(coin)].qualified_amount += amount;
-      } else {
-         pot_info[getcoin_id(coin)].amount += amount;
-      }
-      self.pot_infos.insert(&account, &pot_info);
+      let mut pool = Pool::migrate();
+      assert_eq!(pool.get_state_version(), STATE_VERSION);
+      let coins = pool.get_coins();
+      assert_eq!(coins.len(), 7);
+      assert_eq!((coins[4].symbol.as_str(), coins[4].decimals, coins[4].apr), ("wBTC", 8, 876));
+      assert_eq!(coins[6].token_address, wnear);
+      let status = pool.get_status(alice);
+      assert_eq!(status.user_info[4].amount, 100);
+      assert_eq!(status.total_rewards[4], 5);
+      // alice has not settled yet, her wBTC balance keeps the first tiers
+      assert_eq!(pool.reward_tiers[4][0].checkpoints, 1);
+      assert_eq!(pool.reward_tiers[0][0].checkpoints, 0);
+
+      // the migrated layout loads again and the registry keeps working
+      env::state_write(&pool);
+      pool = Pool::migrate();
+      pool.set_tokenaddress("wBTC".to_string(), account("wbtc.testnet"));
+      assert_eq!(pool.get_coins()[4].token_address, account("wbtc.testnet"));
    }
-   fn withdraw_potinfo(&mut self, account: AccountId, coin: String, amount: u128) {
-      let res = self.pot_infos.get(&account);
-      if res == None {
-         return;
-      }
-      let coin_id = getcoin_id(coin);
-      let mut pot_info = self.pot_infos.get(&account).unwrap();
-      if pot_info[coin_id].qualified_amount >= amount {
-         pot_info[coin_id].qualified_amount -= amount;
-      } else {
-         pot_info[coin_id].qualified_amount = 0;
-         let _amount = amount - pot_info[coin_id].qualified_amount;
-         if pot_info[coin_id].amount >= _amount {
-            pot_info[coin_id].amount -= _amount;
-         } else {
-            pot_info[coin_id].amount = 0;
-         }
+
+   #[test]
+   fn upgrade_deploys_and_migrates() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice);
+      let pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      let code = b"\0asm new code".to_vec();
+      let mut upgrade_context = context.build();
+      upgrade_context.input = code.clone();
+      testing_env!(upgrade_context);
+      pool.upgrade();
+
+      let receipts = get_created_receipts();
+      assert_eq!(receipts[0].receiver_id, env::current_account_id());
+      assert!(matches!(&receipts[0].actions[..], [
+         VmAction::DeployContract { code: deployed },
+         VmAction::FunctionCall { function_name, .. },
+      ] if *deployed == code && function_name == "migrate"));
+      assert_eq!(callback_args("on_upgrade")["code_hash"], hex::encode(env::sha256(&code)));
+
+      callback_context(&mut context, PromiseResult::Failed);
+      assert!(!pool.on_upgrade("hash".to_string()));
+   }
+
+   #[test]
+   #[should_panic(expected = "E049: Not enough gas attached")]
+   fn upgrade_without_enough_gas() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice);
+      let pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      let mut upgrade_context = context.prepaid_gas(GAS_FOR_DEPLOY).build();
+      upgrade_context.input = b"\0asm new code".to_vec();
+      testing_env!(upgrade_context);
+      pool.upgrade();
+   }
+
+   #[test]
+   #[should_panic(expected = "Not Authorized")]
+   fn upgrade_not_owner() {
+      let alice = account("alice.testnet");
+      let mut context = get_context(alice.clone());
+      let pool = setup_pool(&mut context, account("treasury.testnet"));
+
+      let mut upgrade_context = context.predecessor_account_id(alice).build();
+      upgrade_context.input = b"\0asm".to_vec();
+      testing_env!(upgrade_context);
+      pool.upgrade();
+   }
+
+   #[test]
+   fn paused_coin_refuses_deposit() {
+      let alice = account("alice.testnet");
+      let guardian = account("guardian.testnet");
+      let mut context = get_context(alice.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.set_guardian(Some(guardian.clone()));
+      let event = get_logs().pop().unwrap();
+      assert!(event.contains(r#""event":"config_change""#));
+      assert!(event.contains(r#""guardian":"guardian.testnet""#));
+
+      testing_env!(context.predecessor_account_id(guardian).build());
+      let paused = PauseFlags { deposit: true, ..Default::default() };
+      pool.set_paused(Some("USDC".to_string()), paused);
+      assert_eq!(pool.get_paused(Some("USDC".to_string())), paused);
+      assert_eq!(pool.get_paused(None), PauseFlags::default());
+
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      let res = pool.ft_on_transfer(alice.clone(), U128::from(1_000_000), msg.clone());
+      assert!(matches!(res, PromiseOrValue::Value(U128(1_000_000))));
+
+      // other coins keep working
+      testing_env!(context.predecessor_account_id(account("usdt.testnet")).build());
+      pool.ft_on_transfer(alice.clone(), U128::from(1_000_000), msg);
+      let status = pool.get_status(alice);
+      assert_eq!(status.user_info[0].amount, 0);
+      assert_eq!(status.user_info[1].amount, 1_000_000);
+   }
+
+   #[test]
+   #[should_panic(expected = "Only owner can unpause")]
+   fn guardian_cannot_unpause() {
+      let guardian = account("guardian.testnet");
+      let mut context = get_context(guardian.clone());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.set_guardian(Some(guardian.clone()));
+      pool.set_paused(None, PauseFlags { reward: true, ..Default::default() });
+
+      testing_env!(context.predecessor_account_id(guardian).build());
+      pool.set_paused(None, PauseFlags::default());
+   }
+
+   // the treasury tops up the reward budget of the coin behind `token`
+   fn fund(context: &mut VMContextBuilder, pool: &mut Pool, token: &str, amount: u128) {
+      testing_env!(context.predecessor_account_id(account(token)).build());
+      let msg = json!({ "reward_fund": true }).to_string();
+      let res = pool.ft_on_transfer(pool.treasury.clone(), U128(amount), msg);
+      assert!(matches!(res, PromiseOrValue::Value(U128(0))));
+   }
+
+   // alice, bob and carol each deposit 1000 USDC, with a budget well above what they earn
+   fn setup_depositors(context: &mut VMContextBuilder, pool: &mut Pool) -> Vec<AccountId> {
+      fund(context, pool, "usdc.testnet", 1_000_000_000_000);
+      let accounts = vec![account("alice.testnet"), account("bob.testnet"), account("carol.testnet")];
+      for user in accounts.iter() {
+         register(context, pool, user);
+         testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+         let msg = json!({ "qualified": false }).to_string();
+         pool.ft_on_transfer(user.clone(), U128::from(1_000_000_000), msg);
       }
-      self.pot_infos.insert(&account, &pot_info);
+      accounts
    }
-   fn farm_withdraw(
-      &mut self,
-      account: AccountId,
-      coin: String,
-      amount: u128,
-      price: [u128; COIN_COUNT],
-   ) {
-      let current_time = env::block_timestamp_ms();
-      let farm_starttime = self.farm_starttime;
-      let farm_endtime = farm_starttime + FARM_PERIOD;
 
-      //-----------------condition check------------------------------
-      if farm_starttime == 0 || current_time < farm_starttime {
-         return;
+   const HALF_YEAR_NS: u64 = 15_768_000_000_000_000;
+   // 1000 USDC at 21.49% compounding continuously, 1000 * (e^0.10745 - 1) and 1000 * (e^0.2149 - 1)
+   const HALF_YEAR_REWARD: u128 = 113_435_187;
+   const YEAR_REWARD: u128 = 239_737_916;
+
+   #[test]
+   fn lazy_rewards() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury);
+      let accounts = setup_depositors(&mut context, &mut pool);
+
+      // nothing is written until an account is touched, reads include the accrual
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
+      for user in accounts.iter() {
+         assert_eq!(pool.get_status(user.clone()).user_info[0].reward_amount, YEAR_REWARD);
       }
-      if farm_endtime < current_time {
-         return;
+      assert_eq!(pool.get_status(accounts[0].clone()).total_rewards[0], 0);
+
+      // a deposit settles the account, the new balance earns from here on
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "qualified": false }).to_string();
+      pool.ft_on_transfer(accounts[0].clone(), U128::from(1_000_000_000), msg);
+      let status = pool.get_status(accounts[0].clone());
+      assert_eq!(status.user_info[0].reward_amount, YEAR_REWARD);
+      assert_eq!(status.total_rewards[0], YEAR_REWARD);
+      assert!(get_logs().iter().any(|log| log.contains(r#""event":"reward""#)));
+   }
+
+   #[test]
+   fn rewards_follow_elapsed_time() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury);
+      let accounts = setup_depositors(&mut context, &mut pool);
+      let alice = accounts[0].clone();
+
+      // settling twice in the same block pays nothing the second time
+      let reward_time = 1_650_000_000_000_000_000 + HALF_YEAR_NS;
+      testing_env!(context
+         .predecessor_account_id(alice.clone())
+         .block_timestamp(reward_time)
+         .build());
+      pool.withdraw_reserve("USDC".to_string(), U128::from(1), None);
+      pool.withdraw_reserve("USDC".to_string(), U128::from(1), None);
+      let status = pool.get_status(alice.clone());
+      assert_eq!(status.user_info[0].reward_amount, HALF_YEAR_REWARD);
+      assert_eq!(status.total_rewards[0], HALF_YEAR_REWARD);
+      assert_eq!(status.reward_info[0].last_reward_time, reward_time / 1_000_000);
+      let reward_logs = get_logs().iter().filter(|log| log.contains(r#""event":"reward""#)).count();
+      assert_eq!(reward_logs, 1);
+
+      // a second later pays for exactly that second
+      testing_env!(context.block_timestamp(reward_time + 1_000_000_000).build());
+      let index_delta = 2149 * 1_000 * REWARD_INDEX_SCALE / 10_000 / (YEAR as u128);
+      let base = 1_000_000_000 + HALF_YEAR_REWARD;
+      let reward = HALF_YEAR_REWARD + compounded_reward(base, index_delta);
+      assert_eq!(pool.get_status(alice).user_info[0].reward_amount, reward);
+   }
+
+   #[test]
+   fn compounding_ignores_settlement_frequency() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury);
+      let accounts = setup_depositors(&mut context, &mut pool);
+
+      // bob settles every day of the year, alice never does
+      let bob = accounts[1].clone();
+      for day in 1..=365 {
+         testing_env!(context
+            .predecessor_account_id(bob.clone())
+            .block_timestamp(1_650_000_000_000_000_000 + day * 86_400_000_000_000)
+            .build());
+         pool.set_compounding(true);
       }
-      //-----------------No farm yet------------------------
-      let res = self.farm_infos.get(&account);
-      if res == None {
-         return;
+      let alice_reward = pool.get_status(accounts[0].clone()).user_info[0].reward_amount;
+      let bob_reward = pool.get_status(bob).user_info[0].reward_amount;
+      assert_eq!(alice_reward, YEAR_REWARD);
+      // each settlement only rounds down
+      assert!(bob_reward <= alice_reward && alice_reward - bob_reward <= 365);
+   }
+
+   #[test]
+   fn reward_budget_caps_accrual() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      fund(&mut context, &mut pool, "usdc.testnet", 100_000_000);
+      let accounts = [account("alice.testnet"), account("bob.testnet"), account("carol.testnet")];
+      for user in accounts.iter() {
+         register(&mut context, &mut pool, user);
+         testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+         let msg = json!({ "qualified": false }).to_string();
+         pool.ft_on_transfer(user.clone(), U128::from(1_000_000_000), msg);
       }
 
-      //--------------------calc farming amount---------------------
-      let coin_id = getcoin_id(coin);
-      let mut farm_info = self.farm_infos.get(&account).unwrap();
-      let mut total_as_usd = 0;
-      let res = self.user_infos.get(&account);
-      match res {
-         Some(user_info) => {
-            for i in 0..COIN_COUNT {
-               let _price: u128 = price[i].into();
-               total_as_usd += user_info[i].amount * _price;
-            }
-         }
-         None => {}
+      let budget = pool.get_reward_budget("USDC".to_string());
+      assert_eq!(budget.rate, 3_000_000_000 * 2149 / 3_650_000);
+      assert_eq!(budget.runway, Some(mul_div(100_000_000, 86_400_000, budget.rate) as u64));
+
+      // the budget runs out after about two months, the rest of the year pays nothing
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
+      let budget = pool.get_reward_budget("USDC".to_string());
+      assert!(budget.remaining < 3);
+      for user in accounts.iter() {
+         testing_env!(context.predecessor_account_id(user.clone()).build());
+         pool.withdraw_reserve("USDC".to_string(), U128::from(1), None);
       }
-      if total_as_usd > 0 {
-         let _price: u128 = price[coin_id];
-         let mut withdraw_as_usd = amount * _price;
-         if withdraw_as_usd > total_as_usd {
-            withdraw_as_usd = total_as_usd;
-         }
+      let total_rewards = pool.get_status(accounts[0].clone()).total_rewards[0];
+      assert!(total_rewards <= 100_000_000 && total_rewards > 99_999_990);
 
-         let withdraw_amount = withdraw_as_usd * farm_info.amount / total_as_usd;
-         farm_info.amount -= withdraw_amount;
-         self.total_farmed -= withdraw_amount;
-         self.farm_infos.insert(&account, &farm_info);
+      // funding again resumes accrual from then on
+      fund(&mut context, &mut pool, "usdc.testnet", 1_000_000_000);
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 3 * HALF_YEAR_NS).build());
+      let reward = pool.get_status(accounts[0].clone()).user_info[0].reward_amount;
+      let base = 1_000_000_000 + total_rewards / 3;
+      let index_delta = 1_074_500 * REWARD_INDEX_SCALE / 10_000_000;
+      assert_eq!(reward, total_rewards / 3 + compounded_reward(base, index_delta));
+   }
+
+   #[test]
+   fn simple_interest_does_not_compound() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury);
+      let accounts = setup_depositors(&mut context, &mut pool);
+      testing_env!(context.predecessor_account_id(accounts[0].clone()).build());
+      pool.set_compounding(false);
+      assert!(!pool.get_status(accounts[0].clone()).compounding);
+
+      // both settle halfway, only bob's reward earns in the second half
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + HALF_YEAR_NS).build());
+      for user in accounts[..2].iter() {
+         testing_env!(context.predecessor_account_id(user.clone()).build());
+         pool.withdraw_reserve("USDC".to_string(), U128::from(1), None);
       }
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
+      let index_delta = 1_074_500 * REWARD_INDEX_SCALE / 10_000_000;
+      let base = 1_000_000_000 + HALF_YEAR_REWARD;
+      let compounded = HALF_YEAR_REWARD + compounded_reward(base, index_delta);
+      assert_eq!(pool.get_status(accounts[0].clone()).user_info[0].reward_amount, 214_900_000);
+      assert_eq!(pool.get_status(accounts[1].clone()).user_info[0].reward_amount, compounded);
    }
 
-   fn update_farm_info(&mut self, account: AccountId, amount: u128) {
-      let res = self.farm_infos.get(&account);
-      let user_info = match res {
-         Some(mut info) => {
-            info.amount += amount;
-            info
-         }
-         None => FarmInfo {
-            account: account.clone(),
-            amount: amount,
-         },
-      };
-      self.farm_infos.insert(&account, &user_info);
+   #[test]
+   fn failed_claim_rolls_back() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury);
+      let accounts = setup_depositors(&mut context, &mut pool);
+      let alice = accounts[0].clone();
+
+      testing_env!(context
+         .predecessor_account_id(alice.clone())
+         .attached_deposit(1)
+         .block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS)
+         .build());
+      pool.claim_rewards("USDC".to_string());
+      let status = pool.get_status(alice.clone());
+      assert_eq!(status.user_info[0].amount, 1_000_000_000);
+      assert_eq!(status.user_info[0].reward_amount, 0);
+      assert_eq!(status.total_rewards[0], 0);
+      let args = callback_args("on_claim_complete");
+      assert_eq!(args["amount"], YEAR_REWARD.to_string());
+
+      callback_context(&mut context, PromiseResult::Failed);
+      assert_eq!(pool.on_claim_complete(alice.clone(), "USDC".to_string(), U128(YEAR_REWARD)).0, 0);
+      let status = pool.get_status(alice);
+      assert_eq!(status.user_info[0].reward_amount, YEAR_REWARD);
+      assert_eq!(status.total_rewards[0], YEAR_REWARD);
    }
-   //USDC-0 USDT-1 DAI-2 USN-3 wBTC-4 ETH-5 wNEAR-6
-}
 
-#[near_bindgen]
-impl FungibleTokenReceiver for Pool {
-   fn ft_on_transfer(
-      &mut self,
-      sender_id: AccountId,
-      amount: U128,
-      msg: String,
-   ) -> PromiseOrValue<U128> {
-      log!(
-         "in {} tokens from @{} ft_on_transfer, msg = {}",
-         amount.0,
-         sender_id.as_ref(),
-         msg
-      );
+   #[test]
+   fn rewards_paid_from_budget() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      pool.set_buffer_rate("USDC".to_string(), 10_000);
+      let accounts = setup_depositors(&mut context, &mut pool);
+      let payout = 1_000_000_000 + YEAR_REWARD;
 
-      let account = env::signer_account_id();
-      if account == self.treasury {
-         //withdraw
-         let param: WithdrawParam = serde_json::from_str(msg.as_str()).unwrap();
-         self.withdraw(param.account, param.coin, amount.into(), param.price);
-      } else {
-         //deposit
-         let param: DepositParam = serde_json::from_str(msg.as_str()).unwrap();
-         self.deposit(param.coin, amount.into(), param.qualified);
-      }
-      PromiseOrValue::Value(amount)
+      // the buffer only pays the principal
+      testing_env!(context
+         .predecessor_account_id(accounts[0].clone())
+         .attached_deposit(1)
+         .block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS)
+         .build());
+      assert_eq!(pool.withdraw("USDC".to_string(), U128(payout), None), None);
+      assert_eq!(callback_args("ft_transfer")["amount"], json!(payout.to_string()));
+      assert_eq!(pool.get_liquidity_buffer("USDC".to_string()).balance, 2_000_000_000);
+
+      // the treasury gets the reward part of its transfer back
+      testing_env!(context.predecessor_account_id(accounts[1].clone()).build());
+      let request_id = pool.withdraw_reserve("USDC".to_string(), U128(payout), None);
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "account": accounts[1], "price": vec![0; 7], "request_id": request_id });
+      pool.ft_on_transfer(treasury, U128(payout), msg.to_string());
+      let args = callback_args("on_withdraw_complete");
+      let rollback: WithdrawRollback = serde_json::from_value(args["rollback"].clone()).unwrap();
+      callback_context(&mut context, PromiseResult::Successful(vec![]));
+      assert_eq!(pool.on_withdraw_complete(rollback).0, YEAR_REWARD);
    }
-}
 
-#[cfg(test)]
-mod tests {
-   use super::*;
-   use near_sdk::test_utils::{get_logs, VMContextBuilder};
-   use near_sdk::{testing_env, AccountId};
+   #[test]
+   fn defund_rewards() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      fund(&mut context, &mut pool, "usdc.testnet", 1_000_000_000);
 
-   // part of writing unit tests is setting up a mock context
-   // provide a `predecessor` here, it'll modify the default context
-   fn get_context(predecessor: AccountId) -> VMContextBuilder {
-      let mut builder = VMContextBuilder::new();
-      builder.predecessor_account_id(predecessor);
-      builder
+      testing_env!(context.predecessor_account_id(treasury.clone()).attached_deposit(1).build());
+      pool.defund_rewards("USDC".to_string(), U128(400_000_000));
+      assert_eq!(pool.get_reward_budget("USDC".to_string()).funded, 600_000_000);
+      let args = callback_args("ft_transfer");
+      assert_eq!(args["receiver_id"], json!(treasury.to_string()));
+      assert_eq!(args["amount"], json!("400000000"));
+
+      // a failed transfer puts it back in the budget
+      let args = callback_args("on_defund_complete");
+      callback_context(&mut context, PromiseResult::Failed);
+      let unused = pool.on_defund_complete(
+         "USDC".to_string(),
+         account("usdc.testnet"),
+         serde_json::from_value(args["tokens"].clone()).unwrap(),
+      );
+      assert_eq!(unused.0, 0);
+      assert_eq!(pool.get_reward_budget("USDC".to_string()).funded, 1_000_000_000);
    }
 
    #[test]
-   fn debug_get_hash() {
-      // Basic set up for a unit test
-      testing_env!(VMContextBuilder::new().build());
+   #[should_panic(expected = "E050: Not enough unspent reward budget")]
+   fn defund_beyond_remaining() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      setup_depositors(&mut context, &mut pool);
 
-      // Using a unit test to rapidly debug and iterate
-      let debug_solution = "near nomicon ref finance";
-      let debug_hash_bytes = env::sha256(debug_solution.as_bytes());
-      let debug_hash_string = hex::encode(debug_hash_bytes);
-      println!("Let's debug: {:?}", debug_hash_string);
+      // a year of rewards is committed, the whole funding no longer is there to take back
+      testing_env!(context
+         .predecessor_account_id(treasury)
+         .attached_deposit(1)
+         .block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS)
+         .build());
+      pool.defund_rewards("USDC".to_string(), U128(1_000_000_000_000));
    }
 
    #[test]
-   fn main_test() {
-      println!("{:03}",12);
-      // Get Alice as an account ID
-      let alice = AccountId::new_unchecked("alice.testnet".to_string());
-      let owner = None;
-      let treasury = AccountId::new_unchecked("treasury.testnet".to_string());
-      let near_apr = 121;
-      // Set up the testing context and unit test environment
-      let mut context = get_context(alice.clone());
+   fn rewards_in_reward_token() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      let accounts = setup_depositors(&mut context, &mut pool);
+      let alice = accounts[0].clone();
+
+      // two project tokens per USDC unit
+      testing_env!(context.predecessor_account_id(env::current_account_id()).build());
+      let reward_token = RewardToken {
+         token_address: account("proj.testnet"),
+         rate: U128(2 * REWARD_RATE_SCALE),
+      };
+      pool.set_reward_token("USDC".to_string(), Some(reward_token));
+      // the USDC budget goes back to the treasury
+      let args = callback_args("ft_transfer");
+      assert_eq!(args["receiver_id"], json!(treasury.to_string()));
+      assert_eq!(args["amount"], json!("1000000000000"));
+      assert_eq!(pool.get_reward_budget("USDC".to_string()).funded, 0);
+
+      testing_env!(context.predecessor_account_id(account("usdc.testnet")).build());
+      let msg = json!({ "reward_fund": true }).to_string();
+      let res = pool.ft_on_transfer(treasury.clone(), U128(1_000), msg);
+      assert!(matches!(res, PromiseOrValue::Value(U128(1_000))));
+      testing_env!(context.predecessor_account_id(account("proj.testnet")).build());
+      let msg = json!({ "coin": "USDC", "reward_fund": true }).to_string();
+      pool.ft_on_transfer(treasury, U128(2_000_000_000_000), msg);
+      assert_eq!(pool.get_reward_budget("USDC".to_string()).funded, 1_000_000_000_000);
+
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
+      let status = pool.get_status(alice.clone());
+      assert_eq!(status.user_info[0].reward_amount, 0);
+      assert_eq!(status.reward_info[0].token_reward_amount, 429_800_000);
+
+      testing_env!(context.predecessor_account_id(alice.clone()).attached_deposit(1).build());
+      pool.claim_token_rewards("USDC".to_string());
+      let receipts = get_created_receipts();
+      assert_eq!(receipts[0].receiver_id, account("proj.testnet"));
+      assert_eq!(callback_args("on_token_claim_complete")["amount"], "429800000");
+      // positions are credited in the token the reward was paid in
+      let positions = pool.get_positions(alice.clone(), "USDC".to_string());
+      assert_eq!(positions[0].accrued.0, 429_800_000);
+      let status = pool.get_status(alice);
+      assert_eq!(status.reward_info[0].token_reward_amount, 0);
+      assert_eq!(status.total_token_rewards[0], 0);
+   }
+
+   #[test]
+   fn reward_rate_change_reprices_budget() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      let accounts = setup_depositors(&mut context, &mut pool);
+      testing_env!(context.predecessor_account_id(env::current_account_id()).build());
+      let mut reward_token = RewardToken {
+         token_address: account("proj.testnet"),
+         rate: U128(REWARD_RATE_SCALE),
+      };
+      pool.set_reward_token("USDC".to_string(), Some(reward_token.clone()));
+      let msg = json!({ "coin": "USDC", "reward_fund": true }).to_string();
+      testing_env!(context.predecessor_account_id(account("proj.testnet")).build());
+      pool.ft_on_transfer(treasury.clone(), U128(1_000_000_000), msg.clone());
+
+      // at three times the rate, the tokens cover what half a year committed and no more
       testing_env!(context
-         .storage_usage(env::storage_usage())
-         // .attached_deposit(1)
-         .predecessor_account_id(alice.clone())
-         .block_timestamp(12345678)
+         .predecessor_account_id(env::current_account_id())
+         .block_timestamp(1_650_000_000_000_000_000 + HALF_YEAR_NS)
          .build());
+      reward_token.rate = U128(3 * REWARD_RATE_SCALE);
+      pool.set_reward_token("USDC".to_string(), Some(reward_token));
+      let budget = pool.get_reward_budget("USDC".to_string());
+      assert_eq!(budget.remaining, 0);
+      assert!(budget.shortfall > 0);
+
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
+      let mut owed = 0;
+      for user in accounts.iter() {
+         let status = pool.get_status(user.clone());
+         assert_eq!(status.reward_info[0].token_reward_amount, 3 * 107_450_000);
+         owed += status.reward_info[0].token_reward_amount;
+      }
+      assert!(owed <= 1_000_000_000);
+
+      // funding covers the shortfall before accrual resumes
+      let shortfall = budget.shortfall;
+      testing_env!(context.predecessor_account_id(account("proj.testnet")).build());
+      pool.ft_on_transfer(treasury, U128(3 * shortfall + 3_000), msg);
+      let budget = pool.get_reward_budget("USDC".to_string());
+      assert_eq!(budget.shortfall, 0);
+      assert_eq!(budget.remaining, 1_000);
+   }
 
-      let mut pool = Pool::new(owner, treasury.clone());
+   #[test]
+   fn reward_statement() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury);
+      let accounts = setup_depositors(&mut context, &mut pool);
+      let alice = accounts[0].clone();
 
+      let settle_time = 1_650_000_000_000_000_000 + HALF_YEAR_NS;
       testing_env!(context
-         .storage_usage(env::storage_usage())
-         // .attached_deposit(1)
          .predecessor_account_id(alice.clone())
-         .block_timestamp(12345678)
+         .block_timestamp(settle_time)
          .build());
+      pool.withdraw_reserve("USDC".to_string(), U128::from(1), None);
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
 
-      let val = DepositParam {
-         coin: "wBTC".to_string(),
-         qualified: true,
+      let statement = pool.get_reward_statement(alice.clone(), 0, 10);
+      let usdc = &statement.coins[0];
+      let index_delta = 1_074_500 * REWARD_INDEX_SCALE / 10_000_000;
+      assert_eq!((usdc.principal, usdc.accrued), (1_000_000_000, HALF_YEAR_REWARD));
+      assert_eq!(usdc.pending, compounded_reward(1_000_000_000 + HALF_YEAR_REWARD, index_delta));
+      assert_eq!((usdc.apr, usdc.last_reward_time), (2149, settle_time / 1_000_000));
+      assert_eq!(statement.total_credits, 1);
+      let credit = RewardCredit {
+         coin_id: 0,
+         amount: HALF_YEAR_REWARD,
+         paid_in_token: false,
+         time: settle_time / 1_000_000,
       };
-      let arguments = json!(val) // method arguments
-         .to_string();
+      assert_eq!(statement.credits, vec![credit]);
+      assert!(pool.get_reward_statement(alice, 1, 10).credits.is_empty());
+   }
 
-      pool.ft_on_transfer(alice.clone(), U128::from(100_000_000), arguments);
+   #[test]
+   fn paused_rewards() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury);
+      let accounts = setup_depositors(&mut context, &mut pool);
 
       testing_env!(context
-         .storage_usage(env::storage_usage())
-         // .attached_deposit(1)
-         .predecessor_account_id(treasury.clone())
-         .block_timestamp(13355678)
+         .predecessor_account_id(env::current_account_id())
+         .block_timestamp(1_650_000_000_000_000_000 + HALF_YEAR_NS)
          .build());
-      pool.rewards();
+      pool.set_paused(None, PauseFlags { reward: true, ..Default::default() });
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
+      assert_eq!(pool.get_status(accounts[0].clone()).user_info[0].reward_amount, HALF_YEAR_REWARD);
+      pool.set_paused(None, PauseFlags::default());
+      assert_eq!(pool.get_status(accounts[0].clone()).user_info[0].reward_amount, HALF_YEAR_REWARD);
+   }
+
+   #[test]
+   fn scheduled_apr_change() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury);
+      let accounts = setup_depositors(&mut context, &mut pool);
+
+      let start = 1_650_000_000_000;
+      let change_time = start + HALF_YEAR_NS / 1_000_000;
+      testing_env!(context.predecessor_account_id(env::current_account_id()).build());
+      pool.set_apr("USDC".to_string(), 1000, Some(change_time));
+      pool.set_apr("USDC".to_string(), 500, Some(change_time + 1));
+      pool.cancel_apr_change("USDC".to_string(), change_time + 1);
+      let history = pool.get_apr_history("USDC".to_string());
+      assert_eq!(
+         history,
+         vec![AprInfo { apr: 2149, time: start }, AprInfo { apr: 1000, time: change_time }]
+      );
+      assert_eq!(pool.get_coins()[0].apr, 2149);
 
-      let price: [u128; 7] = [500000; 7];
-      pool.withdraw(alice.clone(), "wBTC".to_string(), 50_000_000, price);
+      // one read after the change splits the accrual at its boundary
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
+      let index_delta = (1_074_500 + 500_000) * REWARD_INDEX_SCALE / 10_000_000;
+      let reward = compounded_reward(1_000_000_000, index_delta);
+      assert_eq!(pool.get_status(accounts[0].clone()).user_info[0].reward_amount, reward);
+      assert_eq!(pool.get_coins()[0].apr, 1000);
+   }
+
+   #[test]
+   #[should_panic(expected = "Apr change in the past")]
+   fn apr_change_in_the_past() {
+      let mut context = get_context(env::current_account_id());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      pool.set_apr("USDC".to_string(), 1000, Some(1_649_000_000_000));
+   }
+
+   #[test]
+   fn tiered_rewards() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      // the first 500 USDC at the full rate, the rest at half, qualified accounts get 1.2x
+      let tiers = vec![
+         AprTier { up_to: Some(U128(500_000_000)), multiplier: 10_000 },
+         AprTier { up_to: None, multiplier: 5_000 },
+      ];
+      pool.set_reward_tiers("USDC".to_string(), tiers.clone(), 12_000);
+      assert_eq!(pool.get_reward_tiers("USDC".to_string()).tiers, tiers);
+      let accounts = setup_depositors(&mut context, &mut pool);
+      assert_eq!(pool.get_status(accounts[0].clone()).effective_apr[0], 2149 * 7_500 / 10_000);
+
+      // the whole balance compounds at the principal's 0.75x
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + HALF_YEAR_NS).build());
+      let index_delta = 1_074_500 * REWARD_INDEX_SCALE / 10_000_000;
+      let reward = compounded_reward(1_000_000_000, index_delta * 3 / 4);
+      assert_eq!(reward, 83_923_685);
+      assert_eq!(pool.get_status(accounts[0].clone()).user_info[0].reward_amount, reward);
+
+      // qualifying settles the half year at the old rate, the boost counts from here
+      testing_env!(context.predecessor_account_id(treasury).build());
+      pool.pot_process();
+      assert_eq!(pool.get_status(accounts[0].clone()).total_rewards[0], 3 * reward);
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
+      let reward = reward + compounded_reward(1_000_000_000 + reward, index_delta * 9 / 10);
+      assert_eq!(pool.get_status(accounts[0].clone()).user_info[0].reward_amount, reward);
+   }
+
+   #[test]
+   fn settled_accounts_release_reserved_budget() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury.clone());
+      // reserved at 2x, earned at 0.75x, one account on simple interest
+      let tiers = vec![
+         AprTier { up_to: Some(U128(500_000_000)), multiplier: 10_000 },
+         AprTier { up_to: None, multiplier: 5_000 },
+      ];
+      pool.set_reward_tiers("USDC".to_string(), tiers, 20_000);
+      let accounts = setup_depositors(&mut context, &mut pool);
+      testing_env!(context.predecessor_account_id(accounts[2].clone()).build());
+      pool.set_compounding(false);
+
+      let mut paid = 0;
+      for user in accounts.iter() {
+         testing_env!(context
+            .predecessor_account_id(user.clone())
+            .attached_deposit(1)
+            .block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS)
+            .build());
+         pool.claim_rewards("USDC".to_string());
+         let args = callback_args("on_claim_complete");
+         paid += serde_json::from_value::<U128>(args["amount"].clone()).unwrap().0;
+      }
+      let budget = pool.get_reward_budget("USDC".to_string());
+      assert_eq!(budget.committed, paid);
+      // only what the coin-wide charge rounded up is left reserved
+      assert!(budget.reserved < 10);
+      assert_eq!(budget.remaining, 1_000_000_000_000 - paid - budget.reserved);
+   }
+
+   #[test]
+   fn tier_history_pruned() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury);
+      let accounts = setup_depositors(&mut context, &mut pool);
+
+      for step in 1..=3 {
+         testing_env!(context
+            .predecessor_account_id(env::current_account_id())
+            .block_timestamp(1_650_000_000_000_000_000 + step * HALF_YEAR_NS / 2)
+            .build());
+         pool.set_reward_tiers("USDC".to_string(), vec![], 10_000 + step as u32 * 1_000);
+      }
+      assert_eq!(pool.reward_tiers[0].len(), 4);
+
+      // the old tiers stay while any account still has to settle across them
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
+      for (settled, user) in accounts.iter().enumerate() {
+         assert_eq!(pool.reward_tiers[0].len(), 4);
+         let expected = pool.get_status(user.clone()).user_info[0].reward_amount;
+         testing_env!(context.predecessor_account_id(user.clone()).build());
+         pool.withdraw_reserve("USDC".to_string(), U128::from(1), None);
+         assert_eq!(pool.get_status(user.clone()).user_info[0].reward_amount, expected);
+         assert_eq!(pool.reward_tiers[0].last().unwrap().checkpoints, settled as u64 + 1);
+      }
+      assert_eq!(pool.reward_tiers[0].len(), 1);
+      assert_eq!(pool.get_reward_tiers("USDC".to_string()).qualified_multiplier, 13_000);
+   }
+
+   #[test]
+   #[should_panic(expected = "Last tier must be unbounded")]
+   fn bounded_last_tier() {
+      let mut context = get_context(env::current_account_id());
+      let mut pool = setup_pool(&mut context, account("treasury.testnet"));
+      let tiers = vec![AprTier { up_to: Some(U128(500_000_000)), multiplier: 10_000 }];
+      pool.set_reward_tiers("USDC".to_string(), tiers, 10_000);
+   }
+
+   #[test]
+   fn apr_change_applies_forward() {
+      let treasury = account("treasury.testnet");
+      let mut context = get_context(treasury.clone());
+      let mut pool = setup_pool(&mut context, treasury);
+      let accounts = setup_depositors(&mut context, &mut pool);
 
       testing_env!(context
-         .storage_usage(env::storage_usage())
-         // .attached_deposit(1)
-         .predecessor_account_id(treasury.clone())
-         .block_timestamp(14345678)
+         .predecessor_account_id(env::current_account_id())
+         .block_timestamp(1_650_000_000_000_000_000 + HALF_YEAR_NS)
          .build());
-      pool.farm(price);
-
-      let coin_id = getcoin_id("wBTC".to_string());
-      let res = pool.get_status(alice.clone());
-      println!("{:?}", res.user_info);
-      println!("{:?}", res.farm_info);
+      pool.set_apr("USDC".to_string(), 1000, None);
+      testing_env!(context.block_timestamp(1_650_000_000_000_000_000 + 2 * HALF_YEAR_NS).build());
+      let index_delta = (1_074_500 + 500_000) * REWARD_INDEX_SCALE / 10_000_000;
+      let reward = compounded_reward(1_000_000_000, index_delta);
+      assert_eq!(pool.get_status(accounts[0].clone()).user_info[0].reward_amount, reward);
    }
 }
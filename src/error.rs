@@ -0,0 +1,197 @@
+use near_sdk::env;
+use std::fmt;
+
+// everything the pool fails or refunds with. Codes are stable, messages may be reworded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PoolError{
+    NotAuthorized,
+    OnlyTreasury,
+    OnlyOwnerCanUnpause,
+    NotPaused,
+    UnknownCoin,
+    UnknownToken,
+    CoinAlreadyRegistered,
+    TokenAlreadyRegistered,
+    CoinDisabled,
+    CoinMismatch,
+    InvalidMessage,
+    AccountNotRegistered,
+    StorageDepositRequired,
+    StorageDepositTooLow,
+    StorageWithdrawTooLarge,
+    AccountHasBalance,
+    AccountHasFarm,
+    UsersHaveDeposits,
+    UsersHaveRewards,
+    DepositPaused,
+    WithdrawPaused,
+    FarmPaused,
+    PotPaused,
+    NotEnoughBalance,
+    NothingToWithdraw,
+    NotEnoughReserved,
+    NoSuchWithdrawRequest,
+    WithdrawRequestNotPending,
+    TooManyPendingWithdrawals,
+    NotEnoughToFulfil,
+    NoSuchPosition,
+    TooManyPositions,
+    NoRewardsToClaim,
+    RewardsReserved,
+    NoRewardToken,
+    RateNotPositive,
+    RateTooHigh,
+    AprChangeInPast,
+    AprChangeInEffect,
+    NoAprChange,
+    LastTierBounded,
+    TiersNotAscending,
+    InvalidPriceCount,
+    FarmNotStarted,
+    NoCode,
+    NoState,
+    UnknownStateVersion,
+    MathOverflow,
+    NotEnoughGas,
+    NotEnoughBudget,
+    TooManyDecimals,
+    TermNotSupported,
+}
+
+impl PoolError {
+    pub fn code(&self) -> u32 {
+        match self {
+            PoolError::NotAuthorized => 1,
+            PoolError::OnlyTreasury => 2,
+            PoolError::OnlyOwnerCanUnpause => 3,
+            PoolError::NotPaused => 4,
+            PoolError::UnknownCoin => 5,
+            PoolError::UnknownToken => 6,
+            PoolError::CoinAlreadyRegistered => 7,
+            PoolError::TokenAlreadyRegistered => 8,
+            PoolError::CoinDisabled => 9,
+            PoolError::CoinMismatch => 10,
+            PoolError::InvalidMessage => 11,
+            PoolError::AccountNotRegistered => 12,
+            PoolError::StorageDepositRequired => 13,
+            PoolError::StorageDepositTooLow => 14,
+            PoolError::StorageWithdrawTooLarge => 15,
+            PoolError::AccountHasBalance => 16,
+            PoolError::AccountHasFarm => 17,
+            PoolError::UsersHaveDeposits => 18,
+            PoolError::UsersHaveRewards => 19,
+            PoolError::DepositPaused => 20,
+            PoolError::WithdrawPaused => 21,
+            PoolError::FarmPaused => 22,
+            PoolError::PotPaused => 23,
+            PoolError::NotEnoughBalance => 24,
+            PoolError::NothingToWithdraw => 25,
+            PoolError::NotEnoughReserved => 26,
+            PoolError::NoSuchWithdrawRequest => 27,
+            PoolError::WithdrawRequestNotPending => 28,
+            PoolError::TooManyPendingWithdrawals => 29,
+            PoolError::NotEnoughToFulfil => 30,
+            PoolError::NoSuchPosition => 31,
+            PoolError::TooManyPositions => 32,
+            PoolError::NoRewardsToClaim => 33,
+            PoolError::RewardsReserved => 34,
+            PoolError::NoRewardToken => 35,
+            PoolError::RateNotPositive => 36,
+            PoolError::RateTooHigh => 37,
+            PoolError::AprChangeInPast => 38,
+            PoolError::AprChangeInEffect => 39,
+            PoolError::NoAprChange => 40,
+            PoolError::LastTierBounded => 41,
+            PoolError::TiersNotAscending => 42,
+            PoolError::InvalidPriceCount => 43,
+            PoolError::FarmNotStarted => 44,
+            PoolError::NoCode => 45,
+            PoolError::NoState => 46,
+            PoolError::UnknownStateVersion => 47,
+            PoolError::MathOverflow => 48,
+            PoolError::NotEnoughGas => 49,
+            PoolError::NotEnoughBudget => 50,
+            PoolError::TooManyDecimals => 51,
+            PoolError::TermNotSupported => 52,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            PoolError::NotAuthorized => "Not Authorized",
+            PoolError::OnlyTreasury => "Only treasury",
+            PoolError::OnlyOwnerCanUnpause => "Only owner can unpause",
+            PoolError::NotPaused => "Pool is not paused",
+            PoolError::UnknownCoin => "Not correct coin type",
+            PoolError::UnknownToken => "Unknown token contract",
+            PoolError::CoinAlreadyRegistered => "Coin already registered",
+            PoolError::TokenAlreadyRegistered => "Token already registered",
+            PoolError::CoinDisabled => "Coin is disabled",
+            PoolError::CoinMismatch => "Coin in msg does not match the token",
+            PoolError::InvalidMessage => "Malformed msg",
+            PoolError::AccountNotRegistered => "Account is not registered",
+            PoolError::StorageDepositRequired => "Storage deposit required",
+            PoolError::StorageDepositTooLow => "The attached deposit is less than the minimum storage balance",
+            PoolError::StorageWithdrawTooLarge => "The amount is greater than the available storage balance",
+            PoolError::AccountHasBalance => "Can't unregister the account with the positive balance",
+            PoolError::AccountHasFarm => "Can't unregister the account with the farmed amount without force",
+            PoolError::UsersHaveDeposits => "Users still have deposits",
+            PoolError::UsersHaveRewards => "Users still have rewards",
+            PoolError::DepositPaused => "Deposit is paused",
+            PoolError::WithdrawPaused => "Withdraw is paused",
+            PoolError::FarmPaused => "Farm is paused",
+            PoolError::PotPaused => "Pot is paused",
+            PoolError::NotEnoughBalance => "Not enough balance",
+            PoolError::NothingToWithdraw => "Nothing to withdraw",
+            PoolError::NotEnoughReserved => "Not enough reserved",
+            PoolError::NoSuchWithdrawRequest => "No such withdraw request",
+            PoolError::WithdrawRequestNotPending => "Withdraw request is not pending",
+            PoolError::TooManyPendingWithdrawals => "Too many pending withdrawals",
+            PoolError::NotEnoughToFulfil => "Not enough to fulfil the request",
+            PoolError::NoSuchPosition => "No such position",
+            PoolError::TooManyPositions => "Too many positions",
+            PoolError::NoRewardsToClaim => "No rewards to claim",
+            PoolError::RewardsReserved => "Rewards are reserved for withdrawal",
+            PoolError::NoRewardToken => "No reward token",
+            PoolError::RateNotPositive => "Rate must be positive",
+            PoolError::RateTooHigh => "Rate above 100%",
+            PoolError::AprChangeInPast => "Apr change in the past",
+            PoolError::AprChangeInEffect => "Apr change already in effect",
+            PoolError::NoAprChange => "No apr change at that time",
+            PoolError::LastTierBounded => "Last tier must be unbounded",
+            PoolError::TiersNotAscending => "Tiers must ascend",
+            PoolError::InvalidPriceCount => "Invalid price count",
+            PoolError::FarmNotStarted => "Farm has not started",
+            PoolError::NoCode => "No code to deploy",
+            PoolError::NoState => "No state to migrate",
+            PoolError::UnknownStateVersion => "Unknown state version",
+            PoolError::MathOverflow => "Math overflow",
+            PoolError::NotEnoughGas => "Not enough gas attached",
+            PoolError::NotEnoughBudget => "Not enough unspent reward budget",
+            PoolError::TooManyDecimals => "Decimals above 38",
+            PoolError::TermNotSupported => "Term deposits are not supported",
+        }
+    }
+
+    // aborts the call, the same way require! does on and off chain
+    pub fn panic(self) -> ! {
+        if cfg!(target_arch = "wasm32") {
+            env::panic_str(&self.to_string())
+        } else {
+            panic!("{}", self)
+        }
+    }
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "E{:03}: {}", self.code(), self.message())
+    }
+}
+
+// require! for PoolError
+pub fn ensure(condition: bool, error: PoolError) {
+    if !condition {
+        error.panic()
+    }
+}
@@ -1,3 +1,8 @@
+#![allow(non_snake_case)]
+
+pub mod error;
+pub mod math;
 pub mod msg;
 pub mod contract;
-pub mod util;
\ No newline at end of file
+pub mod migrate;
+pub mod util;
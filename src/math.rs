@@ -0,0 +1,332 @@
+use crate::error::PoolError;
+
+#[allow(clippy::all)]
+mod uint256 {
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+    uint::construct_uint! {
+        pub struct U512(8);
+    }
+}
+pub use uint256::{U256, U512};
+
+pub const REWARD_INDEX_SCALE: u128 = 1_000_000_000_000_000_000;
+// e^x is summed at this precision, so the rounding stays far below a unit of any balance
+const EXP_SCALE: u128 = 1_000_000_000_000_000_000_000_000_000_000_000_000;
+// past e^128 no u128 balance fits anyway
+const EXP_LIMIT: u128 = 128 * REWARD_INDEX_SCALE;
+// ln 2 in EXP_SCALE, rounded down
+const LN_2: u128 = 693_147_180_559_945_309_417_232_121_458_176_568;
+
+// Products are taken in 256 bits and only the result has to fit back into u128, so token
+// amounts with 24 decimals times prices stay exact. What still does not fit fails with
+// PoolError::MathOverflow instead of an arithmetic panic.
+
+// a * b / c rounded down, None when c is 0 or the result passes u128
+pub fn checked_mul_div(a: u128, b: u128, c: u128) -> Option<u128> {
+    wide_mul_div(wide_mul(a, b), 1, U256::from(c))
+}
+
+pub fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    checked_mul_div(a, b, c).unwrap_or_else(|| PoolError::MathOverflow.panic())
+}
+
+pub fn mul(a: u128, b: u128) -> u128 {
+    a.checked_mul(b).unwrap_or_else(|| PoolError::MathOverflow.panic())
+}
+
+pub fn add(a: u128, b: u128) -> u128 {
+    a.checked_add(b).unwrap_or_else(|| PoolError::MathOverflow.panic())
+}
+
+// balance * index_delta / REWARD_INDEX_SCALE
+pub fn accrued_reward(balance: u128, index_delta: u128) -> u128 {
+    mul_div(balance, index_delta, REWARD_INDEX_SCALE)
+}
+
+pub fn wide_mul(a: u128, b: u128) -> U256 {
+    U256::from(a) * U256::from(b)
+}
+
+// a * b / c for a numerator and divisor that are already 256 bits wide
+pub fn wide_mul_div(a: U256, b: u128, c: U256) -> Option<u128> {
+    if c.is_zero() {
+        return None;
+    }
+    let result = a.checked_mul(U256::from(b))? / c;
+    if result > U256::from(u128::MAX) {
+        return None;
+    }
+    Some(result.as_u128())
+}
+
+pub fn pow10(exponent: u32) -> u128 {
+    10u128.checked_pow(exponent).unwrap_or_else(|| PoolError::MathOverflow.panic())
+}
+
+// scale * (numerator / denominator)^exponent rounded down. Powers that fit 256 bits are exact,
+// larger ones are taken by squaring at EXP_SCALE precision
+pub fn scaled_pow(scale: u128, numerator: u128, denominator: u128, exponent: u32) -> u128 {
+    let power = |base: u128| U256::from(base).checked_pow(U256::from(exponent));
+    match (power(numerator), power(denominator)) {
+        (Some(numerator), Some(denominator)) => wide_mul_div(numerator, scale, denominator),
+        _ => rescaled_pow(scale, numerator, denominator, exponent),
+    }
+    .unwrap_or_else(|| PoolError::MathOverflow.panic())
+}
+
+fn rescaled_pow(
+    scale: u128,
+    numerator: u128,
+    denominator: u128,
+    mut exponent: u32,
+) -> Option<u128> {
+    if denominator == 0 {
+        return None;
+    }
+    let one = U512::from(EXP_SCALE);
+    // past this neither the result nor anything multiplied into it can fit u128
+    let limit = U512::from(u128::MAX) * one;
+    let mut base = U512::from(numerator) * one / U512::from(denominator);
+    let mut result = U512::from(scale) * one;
+    while exponent > 0 && !result.is_zero() {
+        if exponent & 1 == 1 {
+            result = result * base / one;
+            if result > limit {
+                return None;
+            }
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = base * base / one;
+            if base > limit {
+                return None;
+            }
+        }
+    }
+    let result = result / one;
+    (result <= U512::from(u128::MAX)).then(|| result.as_u128())
+}
+
+// balance * (e^(exponent / REWARD_INDEX_SCALE) - 1) rounded down, what a balance earns
+// compounding continuously
+pub fn compounded_reward(balance: u128, exponent: u128) -> u128 {
+    compounded(balance, exponent, false)
+}
+
+// the same rounded up, for charging what compounding may cost
+pub fn compounded_cost(balance: u128, exponent: u128) -> u128 {
+    compounded(balance, exponent, true)
+}
+
+fn compounded(balance: u128, exponent: u128, round_up: bool) -> u128 {
+    let scale = U512::from(EXP_SCALE);
+    let growth = exp_m1(exponent, round_up);
+    let mut result = U512::from(balance) * growth / scale;
+    if round_up && !(U512::from(balance) * growth % scale).is_zero() {
+        result += U512::one();
+    }
+    if result > U512::from(u128::MAX) {
+        PoolError::MathOverflow.panic();
+    }
+    result.as_u128()
+}
+
+// e^(x / REWARD_INDEX_SCALE) - 1 in EXP_SCALE, the series summed until its terms run out.
+// Every term rounds down, rounding up adds a bound on what that lost
+fn exp_m1(x: u128, round_up: bool) -> U512 {
+    if x > EXP_LIMIT {
+        PoolError::MathOverflow.panic();
+    }
+    let scale = U512::from(EXP_SCALE);
+    let x = U512::from(x) * U512::from(EXP_SCALE / REWARD_INDEX_SCALE);
+    let mut term = x;
+    let mut sum = U512::zero();
+    let mut n = 1u64;
+    while !term.is_zero() {
+        sum += term;
+        n += 1;
+        term = term * x / (scale * U512::from(n));
+    }
+    if round_up {
+        sum += U512::from(n) * (sum / scale + U512::from(2u8));
+    }
+    sum
+}
+
+// ln(numerator / denominator) in REWARD_INDEX_SCALE rounded down, for numerator >= denominator.
+// The exponent at which compounding turns denominator into numerator
+pub fn ln_ratio(numerator: u128, denominator: u128) -> u128 {
+    if denominator == 0 || numerator < denominator {
+        PoolError::MathOverflow.panic();
+    }
+    let scale = U512::from(EXP_SCALE);
+    // numerator / denominator = 2^halvings * ratio with ratio in [1, 2)
+    let mut halvings = 0u32;
+    while U512::from(numerator) >= U512::from(denominator) << (halvings + 1) {
+        halvings += 1;
+    }
+    let ratio = U512::from(numerator) * scale / (U512::from(denominator) << halvings);
+    // ln(ratio) = 2 * atanh(z) with z = (ratio - 1) / (ratio + 1) below 1/3
+    let z = (ratio - scale) * scale / (ratio + scale);
+    let z_squared = z * z / scale;
+    let mut power = z;
+    let mut sum = U512::zero();
+    let mut n = 1u64;
+    while !power.is_zero() {
+        sum += power / U512::from(n);
+        power = power * z_squared / scale;
+        n += 2;
+    }
+    let ln = U512::from(halvings) * U512::from(LN_2) + sum * U512::from(2u8);
+    (ln / U512::from(EXP_SCALE / REWARD_INDEX_SCALE)).as_u128()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, enough to spread cases over the whole range without a dependency
+    struct Cases(u64);
+    impl Cases {
+        fn next(&mut self) -> u128 {
+            let mut step = || {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0 as u128
+            };
+            let value = step() << 64 | step();
+            // mostly values of realistic size, some anywhere in u128
+            match value % 4 {
+                0 => value,
+                bits => value >> (bits * 32),
+            }
+        }
+    }
+
+    #[test]
+    fn mul_div_matches_u128_when_it_fits() {
+        let mut cases = Cases(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..10_000 {
+            let (a, b, c) = (cases.next(), cases.next(), cases.next().max(1));
+            if let Some(product) = a.checked_mul(b) {
+                assert_eq!(checked_mul_div(a, b, c), Some(product / c));
+            }
+        }
+    }
+
+    #[test]
+    fn mul_div_is_exact_past_u128() {
+        let mut cases = Cases(0x2545_f491_4f6c_dd1d);
+        for _ in 0..10_000 {
+            let (a, b) = (cases.next().max(1), cases.next().max(1));
+            // a * b / b is a whatever the product, a * b / 1 fits only if the product does
+            assert_eq!(checked_mul_div(a, b, b), Some(a));
+            assert_eq!(checked_mul_div(a, b, 1), a.checked_mul(b));
+            assert_eq!(checked_mul_div(a, b, 0), None);
+        }
+    }
+
+    #[test]
+    fn accrued_reward_matches_split_form() {
+        let mut cases = Cases(0xdead_beef_cafe_f00d);
+        for _ in 0..10_000 {
+            let balance = cases.next();
+            let index_delta = cases.next() % (REWARD_INDEX_SCALE * 1_000);
+            let split = (balance / REWARD_INDEX_SCALE).checked_mul(index_delta).and_then(|high| {
+                let low = (balance % REWARD_INDEX_SCALE).checked_mul(index_delta)?;
+                high.checked_add(low / REWARD_INDEX_SCALE)
+            });
+            if let Some(split) = split {
+                assert_eq!(accrued_reward(balance, index_delta), split);
+            }
+        }
+    }
+
+    #[test]
+    fn farm_math_at_max_decimals_and_prices() {
+        // a billion wNEAR at 24 decimals, priced at a million dollars in cents
+        let amount = 1_000_000_000 * pow10(24);
+        let price = 100_000_000;
+        assert!(amount.checked_mul(price).and_then(|value| value.checked_mul(24)).is_none());
+        let farm = mul_div(mul_div(amount, price * 24, pow10(24)), pow10(6), 100_000);
+        assert_eq!(farm, 1_000_000_000 * 100_000_000 * 24 * 10);
+
+        let mut cases = Cases(0x1234_5678_9abc_def0);
+        for _ in 0..10_000 {
+            let (amount, price) = (cases.next() % (u128::MAX / 2), cases.next() % pow10(12));
+            let usd = checked_mul_div(amount, price, pow10(24));
+            // the usd value of any balance is below its raw amount times the price
+            assert!(usd.is_none_or(|usd| wide_mul(usd, pow10(24)) <= wide_mul(amount, price)));
+        }
+    }
+
+    #[test]
+    fn scaled_pow_rounds_down() {
+        assert_eq!(scaled_pow(18, 12, 10, 0), 18);
+        assert_eq!(scaled_pow(18, 12, 10, 1), 21);
+        assert_eq!(scaled_pow(18, 12, 10, 10), 18 * 12u128.pow(10) / 10u128.pow(10));
+        // past where 12^n fits u128
+        assert_eq!(scaled_pow(18, 12, 10, 40), 26_455);
+        // past where 12^n fits 256 bits
+        assert_eq!(scaled_pow(18, 12, 10, 100), 1_490_723_541);
+        assert_eq!(scaled_pow(18, 12, 10, 400), 846_780_647_789_212_113_717_948_779_486_620);
+        assert_eq!(scaled_pow(5, 1, 2, 300), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "E048: Math overflow")]
+    fn scaled_pow_past_u128_has_code() {
+        scaled_pow(18, 12, 10, 480);
+    }
+
+    #[test]
+    fn compounding_is_exact_and_splits() {
+        // e^0.2149 - 1 = 0.2397379...
+        assert_eq!(compounded_reward(1_000_000_000, 2149 * REWARD_INDEX_SCALE / 10_000), 239_737_916);
+        assert_eq!(compounded_reward(5, REWARD_INDEX_SCALE), 8);
+        assert_eq!(compounded_cost(5, REWARD_INDEX_SCALE), 9);
+        assert_eq!(compounded_reward(u128::MAX, 0), 0);
+
+        let mut cases = Cases(0x0f1e_2d3c_4b5a_6978);
+        for _ in 0..1_000 {
+            let balance = cases.next() % pow10(30);
+            let (a, b) = (cases.next() % REWARD_INDEX_SCALE, cases.next() % REWARD_INDEX_SCALE);
+            let whole = compounded_reward(balance, a + b);
+            // settling in two steps compounds the first step's reward over the second, only the
+            // first step's rounding grows with it
+            let first = compounded_reward(balance, a);
+            let split = first + compounded_reward(balance + first, b);
+            assert!(split <= whole && whole - split <= 4);
+            assert!(compounded_cost(balance, a + b) >= whole);
+            assert!(compounded_cost(balance, a + b) - whole <= 1);
+        }
+    }
+
+    #[test]
+    fn ln_ratio_inverts_compounding() {
+        assert_eq!(ln_ratio(1, 1), 0);
+        // ln 2 and ln 10
+        assert_eq!(ln_ratio(2, 1), 693_147_180_559_945_309);
+        assert_eq!(ln_ratio(10, 1), 2_302_585_092_994_045_684);
+
+        let mut cases = Cases(0x7a3f_11c2_9d04_e5b8);
+        for _ in 0..1_000 {
+            let balance = cases.next() % pow10(30) + 1;
+            let target = cases.next() % (balance * 3 + 1);
+            // compounding for the exponent ln_ratio gives stops short of the target by less
+            // than the last unit of the exponent
+            let exponent = ln_ratio(balance + target, balance);
+            assert!(compounded_reward(balance, exponent) <= target);
+            assert!(compounded_reward(balance, exponent + 1) + 1 >= target);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Math overflow")]
+    fn mul_div_overflow_is_a_pool_error() {
+        mul_div(u128::MAX, 2, 1);
+    }
+}
@@ -0,0 +1,54 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
+use near_sdk::{env, AccountId};
+
+use crate::msg::{AmountInfo, FarmInfo, PotInfo, UserInfo};
+
+// Upgrade path
+// ------------
+// The layout version lives under its own storage key, next to the Borsh encoded `Pool`.
+// A deployment without that key is version 1, the layout below.
+// Every release that changes the `Pool` layout bumps STATE_VERSION, keeps a frozen copy of the
+// previous layout here as `PoolVn`, and teaches `Pool::migrate` to convert from it.
+// Deploy the new wasm and call `migrate` in the same transaction, so that no
+// call ever reads the old layout with the new code.
+pub const STATE_VERSION: u32 = 2;
+const STATE_VERSION_KEY: &[u8] = b"v";
+
+// coin table hard-coded in version 1, the index is the coin id
+pub const V1_COINS: [(&str, u32); 7] = [
+    ("USDC", 6),
+    ("USDT", 6),
+    ("DAI", 18),
+    ("USN", 18),
+    ("wBTC", 8),
+    ("ETH", 18),
+    ("wNEAR", 24),
+];
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PoolV1 {
+    pub owner: AccountId,
+    pub treasury: AccountId,
+    pub apr: Vec<u32>,
+    pub user_infos: UnorderedMap<AccountId, Vec<UserInfo>>,
+    pub total_rewards: Vec<u128>,
+    pub amount_history: Vec<AmountInfo>,
+    pub farm_starttime: u64,
+    pub farm_price: u128,
+    pub farm_infos: UnorderedMap<AccountId, FarmInfo>,
+    pub total_farmed: u128,
+    pub pot_infos: UnorderedMap<AccountId, Vec<PotInfo>>,
+    pub token_address: Vec<AccountId>,
+}
+
+pub fn read_state_version() -> u32 {
+    match env::storage_read(STATE_VERSION_KEY) {
+        Some(bytes) => u32::try_from_slice(&bytes).unwrap(),
+        None => 1,
+    }
+}
+
+pub fn write_state_version() {
+    env::storage_write(STATE_VERSION_KEY, &STATE_VERSION.try_to_vec().unwrap());
+}
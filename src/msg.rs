@@ -1,16 +1,45 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault};
-use near_sdk::serde::{Serialize, Deserialize};
-use near_sdk::collections::LookupMap;
+use near_sdk::{env, serde_json, AccountId};
 use near_sdk::json_types::U128;
+use near_sdk::serde::{Serialize, Deserialize};
 use std::fmt;
 
-use crate::contract::{COIN_COUNT};
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Copy, Default, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseFlags{
+    pub deposit: bool,
+    pub withdraw: bool,
+    pub reward: bool,
+    pub farm: bool,
+    pub pot: bool,
+}
+impl fmt::Debug for PauseFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(deposit:{}, withdraw:{}, reward:{}, farm:{}, pot:{})", self.deposit, self.withdraw, self.reward, self.farm, self.pot)
+    }
+}
 
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
+pub struct CoinInfo{
+    pub symbol: String,
+    pub token_address: AccountId,
+    pub decimals: u32,
+    pub apr: u32,
+    pub enabled: bool,
+    pub paused: PauseFlags,
+}
+impl fmt::Debug for CoinInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(symbol:{}, token_address:{}, decimals:{}, apr:{}, enabled:{}, paused:{:?})", self.symbol, self.token_address, self.decimals, self.apr, self.enabled, self.paused)
+    }
+}
+
+// a rate and the time it takes effect from, in msecond
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
 pub struct AprInfo{
-	pub apr: u16,
+	pub apr: u32,
     pub time: u64,
 }
 impl fmt::Debug for AprInfo {
@@ -19,6 +48,37 @@ impl fmt::Debug for AprInfo {
     }
 }
 
+// a balance band, up to `up_to` in token units (the rest when None), earning `multiplier`
+// basis points of the coin apr
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AprTier{
+    pub up_to: Option<U128>,
+    pub multiplier: u32,
+}
+impl fmt::Debug for AprTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(up_to:{:?}, multiplier:{})", self.up_to.map(u128::from), self.multiplier)
+    }
+}
+
+// tiers in effect from the coin's reward index `from_index` on. No tiers is the whole balance
+// at the coin apr, qualified_multiplier applies on top for accounts qualified in the pot.
+// checkpoints counts the accounts last settled under these tiers
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardTiers{
+    pub tiers: Vec<AprTier>,
+    pub qualified_multiplier: u32,
+    pub from_index: u128,
+    pub checkpoints: u64,
+}
+impl fmt::Debug for RewardTiers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(tiers:{:?}, qualified_multiplier:{}, from_index:{}, checkpoints:{})", self.tiers, self.qualified_multiplier, self.from_index, self.checkpoints)
+    }
+}
+
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct UserInfo{
@@ -76,16 +136,176 @@ impl fmt::Debug for PotInfo {
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct DepositParam{
-    pub coin: String,
+    pub coin: Option<String>,
     pub qualified: bool,
+    // term deposits aren't offered, a nonzero term is refunded
+    pub term: Option<u64>,
+}
+
+// one deposit of an account in a coin. id 0 holds the balance from before positions,
+// term is always 0. accrued is the part of the account's settled rewards credited to it, in what
+// they were paid in
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Position{
+    pub id: u64,
+    pub amount: U128,
+    pub start: u64,
+    pub term: u64,
+    pub accrued: U128,
+}
+impl fmt::Debug for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(id:{}, amount:{}, start:{}, term:{}, accrued:{})", self.id, self.amount.0, self.start, self.term, self.accrued.0)
+    }
+}
+
+// the token a coin's rewards are paid in, `rate` of its units per unit of the coin,
+// scaled by REWARD_RATE_SCALE
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardToken{
+    pub token_address: AccountId,
+    pub rate: U128,
+}
+impl fmt::Debug for RewardToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(token_address:{}, rate:{})", self.token_address, self.rate.0)
+    }
+}
+
+// treasury msg topping up the reward budget, {"reward_fund": true}
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FundParam{
+    pub coin: Option<String>,
+    pub reward_fund: bool,
 }
 
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct WithdrawParam{
+    pub account: AccountId,
+    pub coin: Option<String>,
+    pub price: Vec<u128>,
+    // None fulfils the account's oldest pending request of that amount
+    pub request_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum WithdrawStatus{
+    Pending,
+    Fulfilled,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawRequest{
+    pub id: u64,
+    pub account: AccountId,
+    pub coin: String,
+    pub amount: u128,
+    pub time: u64,
+    pub status: WithdrawStatus,
+    // taken from this position first, then from the oldest ones
+    pub position_id: Option<u64>,
+}
+impl fmt::Debug for WithdrawRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(id:{}, account:{}, coin:{}, amount:{}, time:{}, status:{:?}, position_id:{:?})", self.id, self.account, self.coin, self.amount, self.time, self.status, self.position_id)
+    }
+}
+
+// what paid a withdrawal: the treasury for a queued request or a reserve from before the
+// queue, or the coin's liquidity buffer on the contract
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum WithdrawSource{
+    Request(u64),
+    Reserve,
+    Buffer,
+}
+
+// the share of each deposit the contract keeps for self-service withdrawals, in basis
+// points, and the tokens it holds for them
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Default, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LiquidityBuffer{
+    pub rate: u32,
+    pub balance: u128,
+}
+impl fmt::Debug for LiquidityBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(rate:{}, balance:{})", self.rate, self.balance)
+    }
+}
+
+// cumulative reward per unit of a coin, scaled by REWARD_INDEX_SCALE, as of update_time.
+// total_base is the settled amount plus reward of every account, unsettled the budget reserved
+// for rewards accounts have not settled yet
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardIndex{
+    pub index: u128,
+    pub update_time: u64,
+    pub total_base: u128,
+    pub unsettled: u128,
+}
+impl fmt::Debug for RewardIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(index:{}, update_time:{}, total_base:{}, unsettled:{})", self.index, self.update_time, self.total_base, self.unsettled)
+    }
+}
+
+// reward credit the treasury funded for a coin, and how much of it accounts have settled.
+// shortfall is reserved credit a reward token change left uncovered, the next funding pays it
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Default, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardBudget{
+    pub funded: u128,
+    pub committed: u128,
+    pub shortfall: u128,
+}
+impl fmt::Debug for RewardBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(funded:{}, committed:{}, shortfall:{})", self.funded, self.committed, self.shortfall)
+    }
+}
+
+// the coin index an account's balance was last settled at, and when. Kept next to UserInfo
+// so records written before lazy rewards still load
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Default, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardInfo{
+    pub index: u128,
+    pub last_reward_time: u64,
+    // settled rewards of a coin paid in its reward token, in that token
+    pub token_reward_amount: u128,
+}
+impl fmt::Debug for RewardInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(index:{}, last_reward_time:{}, token_reward_amount:{})", self.index, self.last_reward_time, self.token_reward_amount)
+    }
+}
+
+// what take_withdraw took from the account, handed back if the payout fails
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawRollback{
     pub account: AccountId,
     pub coin: String,
-    pub price: [u128; COIN_COUNT],
+    pub amount: U128,
+    pub reward_amount: U128,
+    pub source: WithdrawSource,
+    // the principal taken from each position
+    pub positions: Vec<Position>,
+    pub pot_qualified_amount: U128,
+    pub pot_amount: U128,
+    pub farm_amount: U128,
+    // what the treasury sent beyond the payout, handed back either way
+    pub surplus: U128,
 }
 
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
@@ -94,16 +314,310 @@ pub struct Status{
     pub amount_history: Vec<AmountInfo>,
 
     pub user_info: Vec<UserInfo>,
+    // token_reward_amount includes what is not settled yet
+    pub reward_info: Vec<RewardInfo>,
+    // the apr the account's current balance earns, tiers and pot boost applied
+    pub effective_apr: Vec<u32>,
+    pub compounding: bool,
     pub farm_price: u128,
     pub farm_info: FarmInfo,
     pub farm_starttime: u64,
     pub total_rewards: Vec<u128>,
+    pub total_token_rewards: Vec<u128>,
     pub pot_info: Vec<PotInfo>,
 }
 
+// committed is what accounts have settled, reserved what accrual set aside for the rest. rate
+// is what accrual at most reserves per day at the current balances, runway is how long the
+// remaining budget lasts at it in msecond, None when nothing accrues
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BudgetStatus{
+    pub funded: u128,
+    pub committed: u128,
+    pub reserved: u128,
+    pub remaining: u128,
+    pub shortfall: u128,
+    pub rate: u128,
+    pub runway: Option<u64>,
+}
+
+// a reward settled into an account, in the coin's reward token when paid_in_token
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Default, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardCredit{
+    pub coin_id: u32,
+    pub amount: u128,
+    pub paid_in_token: bool,
+    pub time: u64,
+}
+impl fmt::Debug for RewardCredit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(coin_id:{}, amount:{}, paid_in_token:{}, time:{})", self.coin_id, self.amount, self.paid_in_token, self.time)
+    }
+}
+
+// accrued is what is settled, pending what settling now would add to it
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CoinStatement{
+    pub coin: String,
+    pub principal: u128,
+    pub accrued: u128,
+    pub token_accrued: u128,
+    pub apr: u32,
+    pub last_reward_time: u64,
+    pub pending: u128,
+    pub token_pending: u128,
+}
+
+// coins in coin id order, credits oldest first from the requested index
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardStatement{
+    pub account: AccountId,
+    pub coins: Vec<CoinStatement>,
+    pub total_credits: u64,
+    pub credits: Vec<RewardCredit>,
+}
+
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct FarmResult{
     pub user_info: Vec<UserInfo>,
     pub farm_info: FarmInfo,
 }
+
+//--------------------events (NEP-297)---------------------------
+pub const EVENT_STANDARD: &str = "pool";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositEvent{
+    pub account: AccountId,
+    pub coin: String,
+    pub amount: U128,
+    pub qualified: bool,
+    pub position_id: u64,
+}
+
+// deposit_failed, withdraw_failed and the claims (token claims in the reward token)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AmountEvent{
+    pub account: AccountId,
+    pub coin: String,
+    pub amount: U128,
+}
+
+// withdraw_request and withdraw_cancel
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawRequestEvent{
+    pub id: u64,
+    pub account: AccountId,
+    pub coin: String,
+    pub amount: U128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawEvent{
+    pub account: AccountId,
+    pub coin: String,
+    pub amount: U128,
+    pub reward_amount: U128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardEvent{
+    pub account: AccountId,
+    pub coin: String,
+    pub amount: U128,
+    // set when the amount is in the coin's reward token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reward_token: Option<AccountId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CompoundingEvent{
+    pub account: AccountId,
+    pub compounding: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BufferRateEvent{
+    pub coin: String,
+    pub rate: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardTokenEvent{
+    pub coin: String,
+    pub reward_token: Option<RewardToken>,
+}
+
+// amount as received or handed back, funded is the coin's budget in coin units
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardFundEvent{
+    pub coin: String,
+    pub amount: U128,
+    pub funded: U128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FarmEvent{
+    pub account: AccountId,
+    pub amount: U128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FarmRunEvent{
+    pub accounts: u64,
+    pub amount: U128,
+    pub total_farmed: U128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FarmPriceEvent{
+    pub old_price: U128,
+    pub new_price: U128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PotRolloverEvent{
+    pub accounts: u64,
+    pub removed: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AprEvent{
+    pub coin: String,
+    pub old_apr: u32,
+    pub new_apr: u32,
+    pub effective_time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardTiersEvent{
+    pub coin: String,
+    pub tiers: Vec<AprTier>,
+    pub qualified_multiplier: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ConfigEvent{
+    pub owner: AccountId,
+    pub treasury: AccountId,
+    pub guardian: Option<AccountId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenAddressEvent{
+    pub coin: String,
+    pub old_token_address: AccountId,
+    pub new_token_address: AccountId,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CoinEvent{
+    pub coin: String,
+    pub token_address: AccountId,
+    pub decimals: u32,
+    pub apr: u32,
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseEvent{
+    pub coin: Option<String>,
+    pub paused: PauseFlags,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ResetEvent{
+    pub removed: u64,
+    pub remaining: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UpgradeEvent{
+    pub code_hash: String,
+    pub success: bool,
+    pub state_version: u32,
+}
+
+// `event` and `data` fields of the log, bump EVENT_VERSION when a payload changes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum PoolEvent{
+    Deposit(Vec<DepositEvent>),
+    DepositFailed(Vec<AmountEvent>),
+    WithdrawRequest(Vec<WithdrawRequestEvent>),
+    WithdrawCancel(Vec<WithdrawRequestEvent>),
+    Withdraw(Vec<WithdrawEvent>),
+    WithdrawFailed(Vec<AmountEvent>),
+    Reward(Vec<RewardEvent>),
+    RewardFund(Vec<RewardFundEvent>),
+    RewardDefund(Vec<RewardFundEvent>),
+    RewardTokenChange(Vec<RewardTokenEvent>),
+    BufferRateChange(Vec<BufferRateEvent>),
+    CompoundingChange(Vec<CompoundingEvent>),
+    Claim(Vec<AmountEvent>),
+    ClaimFailed(Vec<AmountEvent>),
+    TokenClaim(Vec<AmountEvent>),
+    TokenClaimFailed(Vec<AmountEvent>),
+    Farm(Vec<FarmEvent>),
+    FarmRun(Vec<FarmRunEvent>),
+    FarmPrice(Vec<FarmPriceEvent>),
+    PotRollover(Vec<PotRolloverEvent>),
+    AprChange(Vec<AprEvent>),
+    RewardTiersChange(Vec<RewardTiersEvent>),
+    ConfigChange(Vec<ConfigEvent>),
+    TokenAddressChange(Vec<TokenAddressEvent>),
+    CoinUpdate(Vec<CoinEvent>),
+    Pause(Vec<PauseEvent>),
+    Reset(Vec<ResetEvent>),
+    Upgrade(Vec<UpgradeEvent>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EventLog{
+    pub standard: String,
+    pub version: String,
+    #[serde(flatten)]
+    pub event: PoolEvent,
+}
+
+impl PoolEvent {
+    pub fn emit(self) {
+        let log = EventLog {
+            standard: EVENT_STANDARD.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: self,
+        };
+        env::log_str(&format!("EVENT_JSON:{}", serde_json::to_string(&log).unwrap()));
+    }
+}
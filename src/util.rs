@@ -1,16 +1,15 @@
-use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault};
 use near_sdk::json_types::U128;
-use crate::contract::{COIN_COUNT};
+use near_sdk::{AccountId, Promise, PromiseOrValue};
 
 pub trait Check{
     fn check_onlyowner(&self);
     fn check_onlytreasury(&self);
-    fn append_amount_history(&mut self, coin: String, amount: u128,  bAdd: bool);
+    fn append_amount_history(&mut self, coin: String, amount: u128,  b_add: bool);
     fn deposit_potinfo(&mut self, account: AccountId, coin: String, amount: u128, qualified: bool);
-    fn withdraw_potinfo(&mut self, account: AccountId, coin: String, amount: u128);
-    fn farm_withdraw(&mut self, account: AccountId, coin: String, amount: u128, price: [u128; COIN_COUNT]);
+    fn withdraw_potinfo(&mut self, account: AccountId, coin: String, amount: u128) -> (u128, u128);
+    fn farm_withdraw(&mut self, account: AccountId, coin: String, amount: u128, price: Vec<u128>) -> u128;
     fn update_farm_info( &mut self, account: AccountId, amount: u128 );
 
-    fn deposit(&mut self, coin: String, amount: u128, qualified: bool);
-    fn withdraw(&mut self, account: AccountId, coin: String, amount: u128, price: [u128; COIN_COUNT]);
-}
\ No newline at end of file
+    fn deposit(&mut self, account: AccountId, coin: String, amount: u128, qualified: bool) -> PromiseOrValue<U128>;
+    fn withdraw(&mut self, account: AccountId, coin: String, amount: u128, price: Vec<u128>, request_id: Option<u64>) -> Promise;
+}